@@ -1,58 +1,182 @@
-use std::{mem::size_of, io::{self, Read}, os::unix::net::UnixStream, path::PathBuf, time::Duration, str};
+use std::{collections::HashMap, io, os::{fd::OwnedFd, unix::net::UnixStream}, path::PathBuf, process::ExitCode, time::Duration};
 
-use common::{shm::SharedMemory, uds};
+use common::{error::Report, shm::{stream::{self, BuildReader, StreamReader}, MappedFile, SealedMemory, SharedMemory}, uds::{self, protocol::{self, capabilities, Request, RequestId, Response}}, Error};
+
+mod output;
+use output::{Output, OutputMode};
+
+/// Requests sent before waiting for a response
+const PIPELINE_DEPTH: usize = 16;
+
+/// Read when no file is given on the command line
+const DEFAULT_FILE: &str = "data/wiki.txt";
+
+/// The server must make progress at least this often while streaming
+const STREAM_TIMEOUT: Duration = Duration::from_secs(30);
+/// Read from the ring and written to stdout at once
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Fails if any file could not be read
+fn main() -> ExitCode {
+    // Progress and errors go to stderr, stdout only gets the file contents
+    let mut output_mode = OutputMode::Raw;
+    let mut files = Vec::new();
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--text" => output_mode = OutputMode::Text,
+            _        => files.push(arg),
+        }
+    }
+    if files.is_empty() {
+        files.push(DEFAULT_FILE.to_owned());
+    }
 
-fn main() {
     let my_pos = std::env::args().next().unwrap();
     let mut dir = PathBuf::from(my_pos);
     dir.pop();
     dir.push("uds");
 
-    println!("Connecting to {}", dir.display());
+    eprintln!("Connecting to {}", dir.display());
 
     match UnixStream::connect(&dir) {
-        Ok(mut stream) => {
-            println!("Connection successful");
-            stream.set_write_timeout(Some(Duration::from_secs(1))).unwrap();
-
-            let file_to_read = "data/wiki.txt";
-            println!("Asking to read {file_to_read}");
-            uds::write_string_null_terminate(&mut stream, file_to_read).unwrap();
-
-            // Receive shared memory info from the server
-            let mut usize_buffer = [0; size_of::<usize>()];
-            stream.read_exact(&mut usize_buffer).unwrap();
-            let shm_size = usize::from_ne_bytes(usize_buffer);
-
-            let mut shm_name = uds::read_null_terminated_string(&mut stream).unwrap();
-            // The string is read without a null terminator
-            // Add the null terminator now to make C APIs happy
-            shm_name.push('\0');
-            println!("Reading {shm_size} bytes from shared memory {shm_name}");
-
-            // Link to shared memory and read data from the server
-            let shm_mem = unsafe {
-                SharedMemory::new(&shm_name, shm_size)
-                    .unwrap()
-            };
-
-            let read_channel = unsafe { shm_mem.as_slice() };
-            let shm_message = str::from_utf8(read_channel).unwrap();
-            assert!(shm_message.is_ascii());
-            println!("{shm_message}");
+        Ok(stream) => {
+            eprintln!("Connection successful");
+            match request(stream, &files, output_mode) {
+                Ok(0) => ExitCode::SUCCESS,
+                Ok(failed) => {
+                    eprintln!("Could not read {failed} of {} files", files.len());
+                    ExitCode::FAILURE
+                }
+                Err(e) => {
+                    eprintln!("Something went wrong: {}", Report(&e));
+                    ExitCode::FAILURE
+                }
+            }
         }
         Err(e) => {
             match e.kind() {
                 io::ErrorKind::ConnectionRefused => {
-                    println!("{}", e);
-                    println!("This could be due to the server not running");
+                    eprintln!("{}", e);
+                    eprintln!("This could be due to the server not running");
                 }
                 _ => {
-                    println!("Something went wrong:");
+                    eprintln!("Something went wrong:");
                     // println!("{:?}", e);
-                    println!("{}", e);
+                    eprintln!("{}", e);
                 }
             }
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Returns how many files the server refused to serve
+fn request(stream: UnixStream, files: &[String], output_mode: OutputMode) -> Result<usize, Error> {
+    stream.set_write_timeout(Some(Duration::from_secs(1)))?;
+
+    let mut reader = uds::Reader::new(&stream);
+    protocol::client_hello(&stream, &mut reader, capabilities::ALL)?;
+
+    // Keep a few requests in flight rather than waiting for each response
+    let mut to_request = (0..).zip(files);
+    let mut pending: HashMap<RequestId, &str> = HashMap::new();
+    let mut failed = 0;
+    loop {
+        while pending.len() < PIPELINE_DEPTH {
+            let Some((id, file_to_read)) = to_request.next() else { break };
+            eprintln!("Asking to read {file_to_read}");
+            let request = Request::OpenFile { id, path: file_to_read.clone() };
+            protocol::send(&stream, &request)?;
+            pending.insert(id, file_to_read);
+        }
+        if pending.is_empty() {
+            break;
+        }
+
+        // Responses may come in any order
+        let response: Response = reader.receive()?;
+        let file_to_read = pending
+                            .remove(&response.id())
+                            .ok_or(uds::Error::MalformedMessage)?;
+        match response {
+            Response::ShmHandle { size, fd, .. } => show_file(size, fd, output_mode)?,
+            Response::Stream { size, fd, .. } => show_stream(size, fd, output_mode)?,
+            Response::FileHandle { size, fd, .. } => show_mapped_file(size, fd, output_mode)?,
+            Response::Error { kind, message, .. } => {
+                eprintln!("The server could not read {file_to_read}: {kind}");
+                eprintln!("Details: {message}");
+                failed += 1;
+            }
         }
     }
+
+    protocol::send(&stream, &Request::Close)?;
+    Ok(failed)
+}
+
+fn show_file(shm_size: u64, shm_fd: OwnedFd, output_mode: OutputMode) -> Result<(), Error> {
+    // Map the shared memory and read data from the server
+    // This checks that the server can no longer modify it
+    let shm_mem = SealedMemory::from_fd(shm_fd)?;
+    if shm_mem.len() as u64 != shm_size {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "shared memory size mismatch").into());
+    }
+    eprintln!("Reading {shm_size} bytes from shared memory");
+
+    let read_channel = shm_mem.as_slice();
+    let mut output = Output::new(output_mode);
+    output.write(read_channel)?;
+    output.finish()?;
+    Ok(())
+}
+
+fn show_mapped_file(size: u64, file_fd: OwnedFd, output_mode: OutputMode) -> Result<(), Error> {
+    // The file itself, straight from the page cache.
+    // Truncating it meanwhile would crash us (SIGBUS): servers only offer this
+    // when asked to, for files that aren't modified in place
+    let file = unsafe { MappedFile::from_fd(file_fd)? };
+    if (file.len() as u64) < size {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the file shrank since the server opened it").into());
+    }
+    eprintln!("Reading {size} bytes from the mapped file");
+
+    let mut output = Output::new(output_mode);
+    output.write(unsafe { &file.as_slice()[..size as usize] })?;
+    output.finish()?;
+    Ok(())
+}
+
+fn show_stream(size: u64, ring_fd: OwnedFd, output_mode: OutputMode) -> Result<(), Error> {
+    eprintln!("Streaming {size} bytes through shared memory");
+    let mut ring = unsafe { SharedMemory::from_fd(ring_fd)? };
+    let mut reader = unsafe {
+        let ring_slice = ring.as_slice_mut();
+        BuildReader::new(ring_slice.as_mut_ptr(), ring_slice.len())?
+            .with_timeout(STREAM_TIMEOUT)
+            .blocking_into()?
+    };
+
+    let res = copy_from_stream(&mut reader, size, Output::new(output_mode));
+    // Also on failure: the server stops writing
+    unsafe { reader.close() };
+    // Only unmap once the reader is done with the ring
+    drop(ring);
+    res
+}
+
+fn copy_from_stream(reader: &mut StreamReader, size: u64, mut output: Output) -> Result<(), Error> {
+    let mut chunk = vec![0; STREAM_CHUNK_SIZE];
+    let mut remaining = size;
+    while remaining > 0 {
+        let chunk_len = remaining.min(STREAM_CHUNK_SIZE as u64) as usize;
+        let read_len = match unsafe { reader.read(&mut chunk[..chunk_len])? } {
+            // The server gave up
+            0 => return Err(stream::Error::PartnerDisconnected.into()),
+            read_len => read_len,
+        };
+        output.write(&chunk[..read_len])?;
+        remaining -= read_len as u64;
+    }
+    output.finish()?;
+    Ok(())
 }
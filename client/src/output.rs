@@ -0,0 +1,123 @@
+//! Writes the file contents to stdout
+
+use std::{io::{self, Stdout, Write}, str};
+
+/// How file contents are written to stdout
+#[derive(Clone, Copy)]
+pub enum OutputMode {
+    /// Bytes as received
+    Raw,
+    /// Decoded as UTF-8, invalid sequences are replaced (`--text`)
+    Text,
+}
+
+/// Takes the content of one file, possibly in several chunks
+pub struct Output<W = Stdout> {
+    mode:    OutputMode,
+    sink:    W,
+    /// Start of a UTF-8 sequence cut at the end of the previous chunk
+    pending: Vec<u8>,
+}
+
+impl Output {
+    pub fn new(mode: OutputMode) -> Self {
+        Self::with_sink(mode, io::stdout())
+    }
+}
+
+impl<W: Write> Output<W> {
+    fn with_sink(mode: OutputMode, sink: W) -> Self {
+        Self {
+            mode,
+            sink,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn write(&mut self, chunk: &[u8]) -> io::Result<()> {
+        let sink = &mut self.sink;
+        match self.mode {
+            OutputMode::Raw  => sink.write_all(chunk),
+            OutputMode::Text => {
+                self.pending.extend_from_slice(chunk);
+                let mut rest = &self.pending[..];
+                loop {
+                    match str::from_utf8(rest) {
+                        Ok(text) => {
+                            sink.write_all(text.as_bytes())?;
+                            rest = &[];
+                            break;
+                        }
+                        Err(e) => {
+                            let (valid, invalid) = rest.split_at(e.valid_up_to());
+                            sink.write_all(valid)?;
+                            match e.error_len() {
+                                Some(error_len) => {
+                                    sink.write_all(char::REPLACEMENT_CHARACTER.encode_utf8(&mut [0; 4]).as_bytes())?;
+                                    rest = &invalid[error_len..];
+                                }
+                                // Wait for the rest of the sequence
+                                None => {
+                                    rest = invalid;
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                }
+                self.pending = rest.to_vec();
+                Ok(())
+            }
+        }
+    }
+
+    /// Must be called after the last chunk
+    pub fn finish(mut self) -> io::Result<W> {
+        if !self.pending.is_empty() {
+            // The file ends in the middle of a sequence
+            self.sink.write_all(char::REPLACEMENT_CHARACTER.encode_utf8(&mut [0; 4]).as_bytes())?;
+        }
+        self.sink.flush()?;
+        Ok(self.sink)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text_of(chunks: &[&[u8]]) -> String {
+        let mut output = Output::with_sink(OutputMode::Text, Vec::new());
+        for chunk in chunks {
+            output.write(chunk).unwrap();
+        }
+        String::from_utf8(output.finish().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn joins_a_sequence_cut_between_chunks() {
+        // "é" is [0xc3, 0xa9], "€" is [0xe2, 0x82, 0xac]
+        assert_eq!(text_of(&[b"caf\xc3", b"\xa9"]), "café");
+        assert_eq!(text_of(&[b"\xe2", b"\x82", b"\xac!"]), "€!");
+    }
+
+    #[test]
+    fn replaces_invalid_bytes() {
+        assert_eq!(text_of(&[b"a\xffb", b"c\x80d"]), "a\u{fffd}bc\u{fffd}d");
+        // A sequence interrupted by another character
+        assert_eq!(text_of(&[b"\xc3", b"x"]), "\u{fffd}x");
+    }
+
+    #[test]
+    fn replaces_a_sequence_cut_by_the_end_of_the_file() {
+        assert_eq!(text_of(&[b"end\xe2\x82"]), "end\u{fffd}");
+    }
+
+    #[test]
+    fn raw_output_is_untouched() {
+        let mut output = Output::with_sink(OutputMode::Raw, Vec::new());
+        output.write(b"caf\xc3").unwrap();
+        output.write(b"\xff").unwrap();
+        assert_eq!(output.finish().unwrap(), b"caf\xc3\xff");
+    }
+}
@@ -0,0 +1,85 @@
+//! Error type covering the whole crate, so that `?` works across modules
+
+use std::{error, fmt, io};
+
+use crate::{promise, shm, uds};
+
+#[derive(Debug)]
+pub enum Error {
+    Shm(shm::Error),
+    Stream(shm::stream::Error),
+    Uds(uds::Error),
+    Promise(promise::Error),
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The details are in the source
+        match self {
+            Error::Shm(e)     => write!(f, "{e}"),
+            Error::Stream(e)  => write!(f, "{e}"),
+            Error::Uds(e)     => write!(f, "{e}"),
+            Error::Promise(e) => write!(f, "{e}"),
+            Error::Io(e)      => write!(f, "{e}"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        // Skip our own level: it displays the same message as the wrapped error
+        match self {
+            Error::Shm(e)     => e.source(),
+            Error::Stream(e)  => e.source(),
+            Error::Uds(e)     => e.source(),
+            Error::Promise(e) => e.source(),
+            Error::Io(e)      => e.source(),
+        }
+    }
+}
+
+impl From<shm::Error> for Error {
+    fn from(value: shm::Error) -> Self {
+        Error::Shm(value)
+    }
+}
+
+impl From<shm::stream::Error> for Error {
+    fn from(value: shm::stream::Error) -> Self {
+        Error::Stream(value)
+    }
+}
+
+impl From<uds::Error> for Error {
+    fn from(value: uds::Error) -> Self {
+        Error::Uds(value)
+    }
+}
+
+impl From<promise::Error> for Error {
+    fn from(value: promise::Error) -> Self {
+        Error::Promise(value)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(value: io::Error) -> Self {
+        Error::Io(value)
+    }
+}
+
+/// Print an error along with all its sources
+pub struct Report<'a>(pub &'a dyn error::Error);
+
+impl fmt::Display for Report<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)?;
+        let mut source = self.0.source();
+        while let Some(cause) = source {
+            write!(f, ": {cause}")?;
+            source = cause.source();
+        }
+        Ok(())
+    }
+}
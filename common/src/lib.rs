@@ -1,3 +1,6 @@
+pub mod error;
 pub mod promise;
 pub mod shm;
 pub mod uds;
+
+pub use error::Error;
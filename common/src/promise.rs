@@ -1,6 +1,25 @@
-use std::{mem, sync::{Mutex, MutexGuard}};
+use std::{error, fmt, mem, sync::{atomic::{AtomicU64, Ordering}, Arc, Condvar, Mutex, MutexGuard, PoisonError}, time::Duration};
+use std::{future::{self, Future}, pin::Pin, task::{Context, Poll, Waker}};
 
-pub struct Promise<T>(Mutex<Result<T, Status>>);
+/* Inter-process promise */
+pub mod shm;
+
+pub struct Promise<T> {
+    data:   Mutex<Result<T, Status>>,
+    /// Notified whenever the promise leaves the `Waiting` state
+    ready:  Condvar,
+    /// Tasks awaiting the promise, with the future that registered them.
+    /// Only locked while holding `data`, so that a wake up can't be missed.
+    wakers: Mutex<Vec<(Owner, Waker)>>,
+}
+
+/// Identifies a future registering wakers, so that it can unregister them later
+type Owner = u64;
+
+/// Futures that never unregister: their wakers go away with `set` or `break_promise`
+const ANONYMOUS: Owner = 0;
+
+static NEXT_OWNER: AtomicU64 = AtomicU64::new(ANONYMOUS + 1);
 
 #[derive(Copy, Clone, Debug)]
 pub enum Status {
@@ -9,21 +28,49 @@ pub enum Status {
     Taken,
 }
 
+#[derive(Debug)]
 pub enum Error {
     WrongState(Status),
     Poisoned,
     AlreadyFull,
+    /// A promise in shared memory holds a state that no process would write
+    InvalidState(u32),
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Status::Waiting => write!(f, "waiting"),
+            Status::Broken  => write!(f, "broken"),
+            Status::Taken   => write!(f, "taken"),
+        }
+    }
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::WrongState(status) => write!(f, "promise is {status}"),
+            Error::Poisoned           => write!(f, "promise lock is poisoned"),
+            Error::AlreadyFull        => write!(f, "promise already holds a value"),
+            Error::InvalidState(state) => write!(f, "promise is in an unknown state ({state})"),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
 impl<T> Promise<T> {
     pub fn new() -> Self {
-        Self (
-            Mutex::new(Err(Status::Waiting))
-        )
+        Self {
+            data:   Mutex::new(Err(Status::Waiting)),
+            ready:  Condvar::new(),
+            wakers: Mutex::new(Vec::new()),
+        }
     }
 
-    fn lock(&self) -> Result<MutexGuard<Result<T, Status>>, Error> {
-        self.0
+    fn lock(&self) -> Result<MutexGuard<'_, Result<T, Status>>, Error> {
+        self.data
             .lock()
             .map_err(|_| Error::Poisoned)
     }
@@ -35,6 +82,7 @@ impl<T> Promise<T> {
             Err(Status::Waiting) => {
                 // Valid, write
                 *data = Ok(value);
+                self.wake_all();
                 Ok(())
             }
             Ok(_) => Err(Error::AlreadyFull),
@@ -42,9 +90,94 @@ impl<T> Promise<T> {
         }
     }
 
-    pub fn try_get(&self) -> Result<T, Error> {
+    /// Signal the waiters that no value will ever come
+    pub fn break_promise(&self) -> Result<(), Error> {
         let mut guard = self.lock()?;
         let data = &mut *guard;
+        match data {
+            Err(Status::Waiting) => {
+                *data = Err(Status::Broken);
+                self.wake_all();
+                Ok(())
+            }
+            Ok(_) => Err(Error::AlreadyFull),
+            Err(status) => Err(Error::WrongState(*status)),
+        }
+    }
+
+    pub fn try_get(&self) -> Result<T, Error> {
+        let mut guard = self.lock()?;
+        Self::take(&mut guard)
+    }
+
+    /// Block until the value is set, or the promise reaches another terminal state
+    pub fn wait(&self) -> Result<T, Error> {
+        let guard = self.lock()?;
+        let mut guard = self.ready
+                            .wait_while(guard, |data| is_waiting(data))
+                            .map_err(|_| Error::Poisoned)?;
+        Self::take(&mut guard)
+    }
+
+    /// Same as `wait`, but gives up after `timeout`.
+    /// Returns `WrongState(Waiting)` on timeout.
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<T, Error> {
+        let guard = self.lock()?;
+        let (mut guard, _timeout_result) = self.ready
+                                            .wait_timeout_while(guard, timeout, |data| is_waiting(data))
+                                            .map_err(|_| Error::Poisoned)?;
+        Self::take(&mut guard)
+    }
+
+    /// Must be called while holding the `data` lock
+    fn wake_all(&self) {
+        self.ready.notify_all();
+        // The list stays consistent even if a holder panicked: don't leave anyone hanging
+        let wakers = mem::take(&mut *self.wakers.lock().unwrap_or_else(PoisonError::into_inner));
+        for (_owner, waker) in wakers {
+            waker.wake();
+        }
+    }
+
+    /// Non-blocking version of `wait` for async code
+    fn poll_get(&self, cx: &mut Context<'_>) -> Poll<Result<T, Error>> {
+        self.poll_get_as(ANONYMOUS, cx)
+    }
+
+    /// Same as `poll_get`, the waker can then be removed with `unregister(owner)`
+    fn poll_get_as(&self, owner: Owner, cx: &mut Context<'_>) -> Poll<Result<T, Error>> {
+        let mut guard = match self.lock() {
+            Ok(guard) => guard,
+            Err(e)    => return Poll::Ready(Err(e)),
+        };
+
+        if !is_waiting(&guard) {
+            return Poll::Ready(Self::take(&mut guard));
+        }
+
+        // Register while holding `data`: set() can't happen in between
+        let mut wakers = self.wakers.lock().unwrap_or_else(PoisonError::into_inner);
+        let registered = wakers
+                            .iter_mut()
+                            .find(|(o, w)| *o == owner && (owner != ANONYMOUS || w.will_wake(cx.waker())));
+        match registered {
+            // A future only needs its latest waker
+            Some((_, waker)) => waker.clone_from(cx.waker()),
+            None             => wakers.push((owner, cx.waker().clone())),
+        }
+        Poll::Pending
+    }
+
+    /// Forget the waker registered by `poll_get_as`,
+    /// for futures that stop polling before a value comes
+    fn unregister(&self, owner: Owner) {
+        self.wakers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|(o, _)| *o != owner);
+    }
+
+    fn take(data: &mut Result<T, Status>) -> Result<T, Error> {
         match data {
             Ok(_) => {
                 // Get the value and clear it
@@ -57,3 +190,259 @@ impl<T> Promise<T> {
         }
     }
 }
+
+fn is_waiting<T>(data: &Result<T, Status>) -> bool {
+    matches!(data, Err(Status::Waiting))
+}
+
+/// Resolves to the value, or to the state that prevents getting it (e.g. `Broken`).
+/// No particular async runtime is needed.
+impl<T> Future for &Promise<T> {
+    type Output = Result<T, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.poll_get(cx)
+    }
+}
+
+impl<T> Default for Promise<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/* Split handles */
+
+/// Create a promise split between a sending and a receiving side.
+/// If the `Resolver` is dropped without a value, e.g. because the thread
+/// holding it panicked, the promise becomes `Broken` and the waiters wake up.
+pub fn channel<T>() -> (Resolver<T>, Receiver<T>) {
+    let promise = Arc::new(Promise::new());
+    let resolver = Resolver {
+        promise: Some(Arc::clone(&promise)),
+    };
+    (resolver, Receiver(promise))
+}
+
+pub struct Resolver<T> {
+    /// Taken once the promise is resolved or broken
+    promise: Option<Arc<Promise<T>>>,
+}
+
+impl<T> Resolver<T> {
+    pub fn resolve(self, value: T) -> Result<(), Error> {
+        self.into_promise().set(value)
+    }
+
+    pub fn break_promise(self) -> Result<(), Error> {
+        self.into_promise().break_promise()
+    }
+
+    /// Disarm the drop handler
+    fn into_promise(mut self) -> Arc<Promise<T>> {
+        // Only empty in the drop handler
+        self.promise.take().unwrap()
+    }
+}
+
+impl<T> Drop for Resolver<T> {
+    fn drop(&mut self) {
+        if let Some(promise) = self.promise.take() {
+            // Nobody to report the error to
+            let _ = promise.break_promise();
+        }
+    }
+}
+
+pub struct Receiver<T>(Arc<Promise<T>>);
+
+impl<T> Receiver<T> {
+    pub fn try_get(&self) -> Result<T, Error> {
+        self.0.try_get()
+    }
+
+    pub fn wait(&self) -> Result<T, Error> {
+        self.0.wait()
+    }
+
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<T, Error> {
+        self.0.wait_timeout(timeout)
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.0.poll_get(cx)
+    }
+}
+
+/* Combinators */
+
+/// Polls several promises on behalf of a single future.
+/// Its wakers are unregistered from all of them once dropped, e.g. when
+/// the first value is in: only `set` and `break_promise` would do it otherwise.
+struct Registrations<'a, T> {
+    promises: &'a [&'a Promise<T>],
+    owner:    Owner,
+}
+
+impl<'a, T> Registrations<'a, T> {
+    fn new(promises: &'a [&'a Promise<T>]) -> Self {
+        Self {
+            promises,
+            owner: NEXT_OWNER.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    fn poll_get(&self, index: usize, cx: &mut Context<'_>) -> Poll<Result<T, Error>> {
+        self.promises[index].poll_get_as(self.owner, cx)
+    }
+
+    fn unregister(&self) {
+        for promise in self.promises {
+            promise.unregister(self.owner);
+        }
+    }
+}
+
+impl<T> Drop for Registrations<'_, T> {
+    fn drop(&mut self) {
+        self.unregister();
+    }
+}
+
+impl<T> Promise<T> {
+    /// Resolves to `f` applied to the value.
+    /// If the value can't be obtained (`Broken`, `Taken`), the error is passed through
+    /// and `f` is not called.
+    pub async fn map<U, F>(&self, f: F) -> Result<U, Error>
+        where F: FnOnce(T) -> U
+    {
+        self.await.map(f)
+    }
+
+    /// Chains another asynchronous step, e.g. waiting for a second promise.
+    /// As with `map`, `f` is not called if the value can't be obtained.
+    pub async fn and_then<U, F, Fut>(&self, f: F) -> Result<U, Error>
+        where F: FnOnce(T) -> Fut,
+              Fut: Future<Output = Result<U, Error>>
+    {
+        let value = self.await?;
+        f(value).await
+    }
+}
+
+/// Resolves to the first value set in any of the promises, with its index.
+/// Only that value is taken, the others stay in their promise.
+///
+/// `Broken` and `Taken` promises are ignored as they will never produce a value.
+/// If all the promises are in that case (or there are none),
+/// resolves to `WrongState(Broken)`.
+pub fn select_first<'a, T>(promises: &'a [&'a Promise<T>]) -> impl Future<Output = Result<(usize, T), Error>> + 'a {
+    let registrations = Registrations::new(promises);
+    future::poll_fn(move |cx| {
+        let mut any_waiting = false;
+        for index in 0..promises.len() {
+            match registrations.poll_get(index, cx) {
+                Poll::Ready(Ok(value)) => {
+                    // The losers keep waiting for nobody
+                    registrations.unregister();
+                    return Poll::Ready(Ok((index, value)));
+                }
+                Poll::Ready(Err(Error::WrongState(Status::Broken | Status::Taken))) => {/* can't win */},
+                Poll::Ready(Err(e)) => {
+                    registrations.unregister();
+                    return Poll::Ready(Err(e));
+                }
+                Poll::Pending => any_waiting = true,
+            }
+        }
+
+        if any_waiting {
+            Poll::Pending
+        }
+        else {
+            Poll::Ready(Err(Error::WrongState(Status::Broken)))
+        }
+    })
+}
+
+/// Resolves to all the values, in order, once every promise is set.
+/// Values are taken as soon as they are available.
+///
+/// Fails as soon as one of the promises is `Broken` or `Taken`, with that state.
+/// The values taken so far are then dropped.
+pub fn join_all<'a, T>(promises: &'a [&'a Promise<T>]) -> impl Future<Output = Result<Vec<T>, Error>> + 'a {
+    let mut values: Vec<Option<T>> = promises.iter().map(|_| None).collect();
+    let registrations = Registrations::new(promises);
+    future::poll_fn(move |cx| {
+        let mut any_waiting = false;
+        for (index, slot) in values.iter_mut().enumerate() {
+            if slot.is_some() {
+                continue;
+            }
+            match registrations.poll_get(index, cx) {
+                Poll::Ready(Ok(value)) => *slot = Some(value),
+                Poll::Ready(Err(e)) => {
+                    registrations.unregister();
+                    return Poll::Ready(Err(e));
+                }
+                Poll::Pending => any_waiting = true,
+            }
+        }
+
+        if any_waiting {
+            Poll::Pending
+        }
+        else {
+            let all_values = mem::take(&mut values)
+                                .into_iter()
+                                .map(Option::unwrap)
+                                .collect();
+            Poll::Ready(Ok(all_values))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registered<T>(promise: &Promise<T>) -> usize {
+        promise.wakers.lock().unwrap().len()
+    }
+
+    #[test]
+    fn select_first_unregisters_the_losers() {
+        let (winner, loser) = (Promise::new(), Promise::new());
+        let promises = [&winner, &loser];
+        let mut cx = Context::from_waker(Waker::noop());
+
+        let mut select = Box::pin(select_first(&promises));
+        assert!(select.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(registered(&loser), 1);
+
+        winner.set(1).unwrap();
+        assert!(matches!(select.as_mut().poll(&mut cx), Poll::Ready(Ok((0, 1)))));
+        assert_eq!(registered(&loser), 0);
+    }
+
+    #[test]
+    fn dropped_combinators_unregister() {
+        let (first, second) = (Promise::<u32>::new(), Promise::new());
+        let promises = [&first, &second];
+        let mut cx = Context::from_waker(Waker::noop());
+
+        let mut join = Box::pin(join_all(&promises));
+        assert!(join.as_mut().poll(&mut cx).is_pending());
+        // Polling again doesn't pile up wakers
+        assert!(join.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(registered(&first), 1);
+
+        drop(join);
+        assert_eq!(registered(&first), 0);
+        assert_eq!(registered(&second), 0);
+    }
+}
@@ -0,0 +1,148 @@
+//! A promise that lives in shared memory, so that the value can be set
+//! by one process and retrieved by another.
+//!
+//! The state is a single atomic word in the mapping, using the same
+//! `Waiting`/`Broken`/`Taken` states as `Promise`. Waiters sleep on it with a futex.
+//! All zeroes is a valid `Waiting` promise: allocate it with `shm::arena::Arena`.
+//!
+//! If the setter dies while copying the value, the promise stays pending forever:
+//! use `wait_timeout` if that can happen. Since any process with the mapping can
+//! write the state, states that no `ShmPromise` would write are reported as
+//! `InvalidState` rather than waited on.
+
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+use std::ptr;
+
+use crate::shm::arena::ShmSafe;
+use super::{Error, Status};
+
+const WAITING: u32 = 0;
+/// The setter is copying the value
+const WRITING: u32 = 1;
+const FULL:    u32 = 2;
+const TAKEN:   u32 = 3;
+const BROKEN:  u32 = 4;
+
+#[repr(C)]
+pub struct ShmPromise<T> {
+    state: AtomicU32,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: ShmSafe + Copy> ShmSafe for ShmPromise<T> {}
+unsafe impl<T: Send> Send for ShmPromise<T> {}
+unsafe impl<T: Send> Sync for ShmPromise<T> {}
+
+fn status_of(state: u32) -> Error {
+    match state {
+        FULL | WRITING => Error::AlreadyFull,
+        TAKEN          => Error::WrongState(Status::Taken),
+        BROKEN         => Error::WrongState(Status::Broken),
+        WAITING        => Error::WrongState(Status::Waiting),
+        _              => Error::InvalidState(state),
+    }
+}
+
+impl<T: ShmSafe + Copy> ShmPromise<T> {
+    pub fn set(&self, value: T) -> Result<(), Error> {
+        self.state
+            .compare_exchange(WAITING, WRITING, Ordering::Acquire, Ordering::Relaxed)
+            .map_err(status_of)?;
+
+        // We're the only writer from now on
+        unsafe { ptr::write_volatile(self.value.get(), value) };
+        self.state.store(FULL, Ordering::Release);
+        self.wake_all();
+        Ok(())
+    }
+
+    /// Signal the other side that no value will ever come
+    pub fn break_promise(&self) -> Result<(), Error> {
+        self.state
+            .compare_exchange(WAITING, BROKEN, Ordering::Relaxed, Ordering::Relaxed)
+            .map_err(status_of)?;
+        self.wake_all();
+        Ok(())
+    }
+
+    pub fn try_get(&self) -> Result<T, Error> {
+        match self.state.compare_exchange(FULL, TAKEN, Ordering::Acquire, Ordering::Relaxed) {
+            // The value can no longer change once FULL
+            Ok(_) => Ok(unsafe { ptr::read_volatile(self.value.get()) }),
+            // A WRITING value is not available yet
+            Err(FULL | WRITING) => Err(Error::WrongState(Status::Waiting)),
+            Err(state)          => Err(status_of(state)),
+        }
+    }
+
+    /// Block until the value is set or the promise is broken.
+    /// Never returns if the setter dies while writing the value.
+    pub fn wait(&self) -> Result<T, Error> {
+        loop {
+            match self.try_get() {
+                Err(Error::WrongState(Status::Waiting)) => {/* sleep below */},
+                res => return res,
+            }
+            self.sleep_while_pending(None);
+        }
+    }
+
+    /// Same as `wait`, but gives up after `timeout`.
+    /// Returns `WrongState(Waiting)` on timeout.
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<T, Error> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            match self.try_get() {
+                Err(Error::WrongState(Status::Waiting)) => {/* sleep below */},
+                res => return res,
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::WrongState(Status::Waiting));
+            }
+            self.sleep_while_pending(Some(deadline - now));
+        }
+    }
+
+    /// Sleep as long as the state is WAITING or WRITING.
+    /// Can return early, callers need to re-check the state.
+    fn sleep_while_pending(&self, timeout: Option<Duration>) {
+        let state = self.state.load(Ordering::Relaxed);
+        if state != WAITING && state != WRITING {
+            return;
+        }
+        futex_wait(&self.state, state, timeout);
+    }
+
+    fn wake_all(&self) {
+        futex_wake_all(&self.state);
+    }
+}
+
+/* Futex */
+
+// We don't use FUTEX_PRIVATE_FLAG: the waiters are in other processes
+
+fn futex_wait(word: &AtomicU32, expected: u32, timeout: Option<Duration>) {
+    let timespec = timeout.map(|t| libc::timespec {
+        tv_sec:  t.as_secs().min(libc::time_t::MAX as u64) as libc::time_t,
+        tv_nsec: t.subsec_nanos() as _,
+    });
+    let timespec_ptr = timespec
+                        .as_ref()
+                        .map_or(ptr::null(), |t| t as *const libc::timespec);
+
+    // Errors are EAGAIN (the value already changed), ETIMEDOUT and EINTR:
+    // in all cases the caller re-checks the state
+    unsafe {
+        libc::syscall(libc::SYS_futex, word.as_ptr(), libc::FUTEX_WAIT, expected, timespec_ptr);
+    }
+}
+
+fn futex_wake_all(word: &AtomicU32) {
+    unsafe {
+        libc::syscall(libc::SYS_futex, word.as_ptr(), libc::FUTEX_WAKE, i32::MAX);
+    }
+}
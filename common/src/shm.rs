@@ -1,68 +1,673 @@
-use libc::{MAP_SHARED, O_RDWR, O_CREAT, PROT_WRITE, S_IRUSR, S_IWUSR};
-use libc::{c_char, off_t, c_int};
-use std::{ptr, slice};
+use libc::{MAP_SHARED, O_RDWR, O_CREAT, O_EXCL, PROT_READ, PROT_WRITE, S_IRUSR, S_IWUSR, MAP_FAILED, EEXIST, MFD_CLOEXEC, MFD_ALLOW_SEALING, MREMAP_MAYMOVE};
+use libc::{MFD_HUGETLB, MAP_HUGETLB, MAP_POPULATE, MADV_HUGEPAGE};
+use libc::{F_ADD_SEALS, F_GET_SEALS, F_SEAL_WRITE, F_SEAL_SHRINK, F_SEAL_GROW, F_SEAL_SEAL};
+use libc::{c_char, c_uint, off_t, c_int};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, IntoRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{error, fmt, io, mem, process, ptr, slice};
 
 /* Shared Memory Stream */
 pub mod stream;
+/* Shared memory that can grow */
+pub mod growable;
+/* Typed allocations inside shared memory */
+pub mod arena;
+/* Inter-process synchronization */
+pub mod sync;
 
 type Fd = c_int;
 
 pub struct SharedMemory {
     fd:        Fd,
     data_ptr:  *mut u8,
-    data_size: usize
+    data_size: usize,
+    /// Null-terminated name of the underlying shm object.
+    /// Anonymous memory (see `anonymous`) doesn't have one.
+    name:      Option<String>,
+    /// Re-applied every time the memory is remapped
+    options:   Builder,
 }
 
 #[derive(Debug)]
 pub enum Error {
     StringNotAscii,
     StringNotNullTerminated,
-    StringEmpty
+    StringEmpty,
+    OpenFailed(io::Error),
+    StatFailed(io::Error),
+    TruncateFailed(io::Error),
+    MapFailed(io::Error),
+    /// Explicit huge pages only work for anonymous memory
+    HugePagesUnsupported,
+    /// The system has no huge pages available (see /proc/sys/vm/nr_hugepages),
+    /// or the size is not a multiple of the huge page size
+    HugePagesUnavailable(io::Error),
+    /// Transparent huge pages are disabled (see /sys/kernel/mm/transparent_hugepage)
+    AdviseFailed(io::Error),
+    /// Usually means that RLIMIT_MEMLOCK is too low
+    LockFailed(io::Error),
+    MutexFailed(io::Error),
+    /// A previous owner died and the lock was unlocked without being made consistent
+    MutexNotRecoverable,
+    CondvarFailed(io::Error),
+    /// Shared memory can only grow, peers would crash accessing the truncated part
+    CannotShrink,
+    /// The memory must hold at least this many bytes
+    NotLargeEnough(usize),
+    /// The offset points outside of the mapping
+    OutOfBounds,
+    /// The offset or the requested alignment doesn't fit the type
+    Misaligned,
+    SealFailed(io::Error),
+    /// The memory lacks some of the seals required by `SealedMemory`.
+    /// Holds the seals that are actually set.
+    NotSealed(c_int),
+    /// Could not find a free name after trying this many times
+    NoUniqueName(usize),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::StringNotAscii          => write!(f, "shared memory name is not ASCII"),
+            Error::StringNotNullTerminated => write!(f, "shared memory name is not null-terminated"),
+            Error::StringEmpty             => write!(f, "shared memory name is empty"),
+            Error::OpenFailed(_)           => write!(f, "could not open shared memory"),
+            Error::StatFailed(_)           => write!(f, "could not get the size of shared memory"),
+            Error::TruncateFailed(_)       => write!(f, "could not set the size of shared memory"),
+            Error::MapFailed(_)            => write!(f, "could not map shared memory"),
+            Error::HugePagesUnsupported    => write!(f, "explicit huge pages require anonymous shared memory"),
+            Error::HugePagesUnavailable(_) => write!(f, "huge pages are not available"),
+            Error::AdviseFailed(_)         => write!(f, "transparent huge pages are not available"),
+            Error::LockFailed(_)           => write!(f, "could not lock shared memory in RAM"),
+            Error::MutexFailed(_)          => write!(f, "shared memory mutex operation failed"),
+            Error::MutexNotRecoverable     => write!(f, "shared memory mutex is not recoverable"),
+            Error::CondvarFailed(_)        => write!(f, "shared memory condition variable operation failed"),
+            Error::CannotShrink            => write!(f, "shared memory can't shrink"),
+            Error::NotLargeEnough(size)    => write!(f, "shared memory must be at least {size} bytes"),
+            Error::OutOfBounds             => write!(f, "offset is out of the shared memory bounds"),
+            Error::Misaligned              => write!(f, "offset is not properly aligned"),
+            Error::SealFailed(_)           => write!(f, "could not seal shared memory"),
+            Error::NotSealed(seals)        => write!(f, "shared memory is not sealed (seals: {seals:#x})"),
+            Error::NoUniqueName(attempts)  => write!(f, "no free shared memory name after {attempts} attempts"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::OpenFailed(e)
+            | Error::StatFailed(e)
+            | Error::TruncateFailed(e)
+            | Error::MapFailed(e)
+            | Error::HugePagesUnavailable(e)
+            | Error::AdviseFailed(e)
+            | Error::LockFailed(e)
+            | Error::MutexFailed(e)
+            | Error::CondvarFailed(e)
+            | Error::SealFailed(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/* Unique names */
+
+/// How many fresh names we try before giving up in `create_unique`
+const MAX_NAME_ATTEMPTS: usize = 16;
+
+static NAME_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+fn random_u64() -> u64 {
+    let mut value: u64 = 0;
+    let value_ptr = (&mut value as *mut u64).cast();
+    let len = std::mem::size_of::<u64>();
+    let read = unsafe { libc::getrandom(value_ptr, len, libc::GRND_NONBLOCK) };
+    if read != len as isize {
+        // Not enough entropy yet: the clock is good enough to avoid collisions,
+        // the O_EXCL retry loop takes care of the rest
+        value = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_nanos() as u64)
+                    .unwrap_or(0);
+    }
+    value
+}
+
+/// Generate a null-terminated shm name that no other request should be using.
+/// The name is made of the given prefix, our pid, a process-wide counter and a random suffix.
+pub fn unique_name(prefix: &str) -> String {
+    let pid = process::id();
+    let count = NAME_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let suffix = random_u64();
+    format!("/{prefix}-{pid}-{count}-{suffix:016x}\0")
+}
+
+/// Make sure that the given name can be passed to the C APIs
+fn validate_name(shm_name: &str) -> Result<*const c_char, Error> {
+    if !shm_name.is_ascii() {
+        return Err(Error::StringNotAscii)
+    }
+    let name_bytes = shm_name.as_bytes();
+
+    match name_bytes.last() {
+        None       => return Err(Error::StringEmpty),
+        Some(b'\0') => {/* valid, nothing to do */},
+        Some(_)    => return Err(Error::StringNotNullTerminated),
+    }
+    Ok(name_bytes.as_ptr() as *const c_char)
+}
+
+/* Builder */
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HugePages {
+    /// Regular pages
+    #[default]
+    Off,
+    /// Ask the kernel to back the memory with huge pages when it can (`MADV_HUGEPAGE`)
+    Transparent,
+    /// Use reserved huge pages (`MFD_HUGETLB`/`MAP_HUGETLB`).
+    /// The size of the memory must be a multiple of the huge page size.
+    Explicit,
+}
+
+/// Options for mapping shared memory.
+/// Each of them fails the creation if the system refuses it.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Builder {
+    huge_pages: HugePages,
+    populate:   bool,
+    lock:       bool,
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn huge_pages(mut self, huge_pages: HugePages) -> Self {
+        self.huge_pages = huge_pages;
+        self
+    }
+
+    /// Pre-fault the whole mapping (`MAP_POPULATE`).
+    /// The kernel does this on a best effort basis: combine with `lock`
+    /// to make sure that the memory stays resident.
+    pub fn populate(mut self, populate: bool) -> Self {
+        self.populate = populate;
+        self
+    }
+
+    /// Lock the mapping in RAM (`mlock`)
+    pub fn lock(mut self, lock: bool) -> Self {
+        self.lock = lock;
+        self
+    }
+
+    /// See `SharedMemory::new`
+    ///
+    /// # Safety
+    /// The object must not be resized by another process while it is mapped
+    pub unsafe fn open(self, shm_name: &str, shm_size: usize) -> Result<SharedMemory, Error> {
+        self.check_named()?;
+        let c_name = validate_name(shm_name)?;
+        let fd = libc::shm_open(c_name, O_RDWR | O_CREAT, S_IRUSR | S_IWUSR);
+        if fd < 0 {
+            return Err(Error::OpenFailed(io::Error::last_os_error()));
+        }
+        SharedMemory::create(fd, shm_size, Some(shm_name.to_owned()), self)
+    }
+
+    /// See `SharedMemory::create_unique`
+    ///
+    /// # Safety
+    /// The object must not be resized by another process while it is mapped
+    pub unsafe fn create_unique(self, prefix: &str, shm_size: usize) -> Result<SharedMemory, Error> {
+        self.check_named()?;
+        for _ in 0..MAX_NAME_ATTEMPTS {
+            let shm_name = unique_name(prefix);
+            let c_name = validate_name(&shm_name)?;
+            let fd = libc::shm_open(c_name, O_RDWR | O_CREAT | O_EXCL, S_IRUSR | S_IWUSR);
+            if fd >= 0 {
+                return SharedMemory::create(fd, shm_size, Some(shm_name), self);
+            }
+
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(EEXIST) {
+                return Err(Error::OpenFailed(err));
+            }
+            // Someone else owns this name, try the next one
+        }
+        Err(Error::NoUniqueName(MAX_NAME_ATTEMPTS))
+    }
+
+    /// See `SharedMemory::anonymous`
+    ///
+    /// # Safety
+    /// The object must not be resized by another process while it is mapped
+    pub unsafe fn anonymous(self, debug_name: &str, shm_size: usize) -> Result<SharedMemory, Error> {
+        let c_name = validate_name(debug_name)?;
+        let mut flags: c_uint = MFD_CLOEXEC | MFD_ALLOW_SEALING;
+        if self.huge_pages == HugePages::Explicit {
+            flags |= MFD_HUGETLB;
+        }
+
+        let fd = libc::memfd_create(c_name, flags);
+        if fd < 0 {
+            let err = io::Error::last_os_error();
+            return match self.huge_pages {
+                HugePages::Explicit => Err(Error::HugePagesUnavailable(err)),
+                _                   => Err(Error::OpenFailed(err)),
+            };
+        }
+        SharedMemory::create(fd, shm_size, None, self)
+    }
+
+    /// See `SharedMemory::from_fd`
+    ///
+    /// # Safety
+    /// The object must not be resized by another process while it is mapped
+    pub unsafe fn from_fd(self, fd: OwnedFd) -> Result<SharedMemory, Error> {
+        SharedMemory::map_whole(fd.into_raw_fd(), PROT_READ | PROT_WRITE, self)
+    }
+
+    fn check_named(&self) -> Result<(), Error> {
+        // /dev/shm is a regular tmpfs
+        match self.huge_pages {
+            HugePages::Explicit => Err(Error::HugePagesUnsupported),
+            _                   => Ok(()),
+        }
+    }
+
+    fn mmap_flags(&self) -> c_int {
+        let mut flags = MAP_SHARED;
+        if self.huge_pages == HugePages::Explicit {
+            flags |= MAP_HUGETLB;
+        }
+        if self.populate {
+            flags |= MAP_POPULATE;
+        }
+        flags
+    }
+
+    /// Apply the options that can only be set once the memory is mapped
+    unsafe fn after_map(&self, addr: *mut u8, shm_size: usize) -> Result<(), Error> {
+        if self.huge_pages == HugePages::Transparent
+            && libc::madvise(addr.cast(), shm_size, MADV_HUGEPAGE) < 0
+        {
+            return Err(Error::AdviseFailed(io::Error::last_os_error()));
+        }
+        if self.lock && libc::mlock(addr.cast(), shm_size) < 0 {
+            return Err(Error::LockFailed(io::Error::last_os_error()));
+        }
+        Ok(())
+    }
 }
 
 impl SharedMemory {
+    /// Open the shared memory object with the given name, creating it if needed.
+    /// Use `Builder` for more mapping options.
+    ///
+    /// # Safety
+    /// The object must not be resized by another process while it is mapped
     pub unsafe fn new(shm_name: &str, shm_size: usize) -> Result<Self, Error> {
-        // Validate the passed name
-        if !shm_name.is_ascii() {
-            return Err(Error::StringNotAscii)
+        Builder::new().open(shm_name, shm_size)
+    }
+
+    /// Create a brand new shared memory object with a name generated by `unique_name`.
+    /// Use `name` to retrieve the chosen name.
+    ///
+    /// # Safety
+    /// The object must not be resized by another process while it is mapped
+    pub unsafe fn create_unique(prefix: &str, shm_size: usize) -> Result<Self, Error> {
+        Builder::new().create_unique(prefix, shm_size)
+    }
+
+    /// Create shared memory that is not visible in the file system (backed by `memfd_create`).
+    /// Other processes can only access it if we send them our file descriptor,
+    /// see `uds::send_fd`. The memory is released when the last process drops it.
+    ///
+    /// `debug_name` only shows up in `/proc/<pid>/fd`, it doesn't need to be unique.
+    ///
+    /// # Safety
+    /// The object must not be resized by another process while it is mapped
+    pub unsafe fn anonymous(debug_name: &str, shm_size: usize) -> Result<Self, Error> {
+        Builder::new().anonymous(debug_name, shm_size)
+    }
+
+    /// Map shared memory received from another process.
+    /// The size of the mapping is the current size of the object.
+    ///
+    /// # Safety
+    /// The object must not be resized by another process while it is mapped
+    pub unsafe fn from_fd(fd: OwnedFd) -> Result<Self, Error> {
+        Builder::new().from_fd(fd)
+    }
+
+    /// Map the object with its current size.
+    /// Takes ownership of the fd, closing it on failure.
+    unsafe fn map_whole(fd: Fd, prot: c_int, options: Builder) -> Result<Self, Error> {
+        let mut stat: libc::stat = mem::zeroed();
+        if libc::fstat(fd, &mut stat) < 0 {
+            return Self::close_on_failure(fd, Error::StatFailed);
         }
-        let name_bytes = shm_name.as_bytes();
+        Self::map(fd, stat.st_size as usize, prot, None, options)
+    }
 
-        match name_bytes.last() {
-            None       => return Err(Error::StringEmpty),
-            Some(b'\0') => {/* valid, nothing to do */},
-            Some(_)    => return Err(Error::StringNotNullTerminated),
+    /// Set the size of a newly created object, then map it.
+    /// Takes ownership of the fd, closing it on failure.
+    unsafe fn create(fd: Fd, shm_size: usize, name: Option<String>, options: Builder) -> Result<Self, Error> {
+        if libc::ftruncate(fd, shm_size as off_t) < 0 {
+            return match options.huge_pages {
+                HugePages::Explicit => Self::close_on_failure(fd, Error::HugePagesUnavailable),
+                _                   => Self::close_on_failure(fd, Error::TruncateFailed),
+            };
         }
-        let c_name = name_bytes.as_ptr() as *const c_char;
+        Self::map(fd, shm_size, PROT_READ | PROT_WRITE, name, options)
+    }
 
-        let null = ptr::null_mut();
-        let fd   = libc::shm_open(c_name, O_RDWR | O_CREAT, S_IRUSR | S_IWUSR);
-        let _res = libc::ftruncate(fd, shm_size as off_t);
-        let addr = libc::mmap(null, shm_size, PROT_WRITE, MAP_SHARED, fd, 0);
+    /// Report the last OS error and close the fd
+    unsafe fn close_on_failure<T>(fd: Fd, to_error: fn(io::Error) -> Error) -> Result<T, Error> {
+        let err = io::Error::last_os_error();
+        libc::close(fd);
+        Err(to_error(err))
+    }
 
-        Ok(
-            Self {
-                fd,
-                data_ptr:  addr as *mut u8,
-                data_size: shm_size
+    /// Takes ownership of the fd, closing it on failure
+    unsafe fn map(fd: Fd, shm_size: usize, prot: c_int, name: Option<String>, options: Builder) -> Result<Self, Error> {
+        match mmap_fd(fd, shm_size, prot, &options) {
+            Ok(addr) => Ok(
+                Self {
+                    fd,
+                    data_ptr:  addr,
+                    data_size: shm_size,
+                    name,
+                    options,
+                }
+            ),
+            Err(e) => {
+                libc::close(fd);
+                Err(e)
             }
-        )
+        }
     }
 
+    /// Make the memory immutable for everyone, including us.
+    /// Only works on memory created with `anonymous`.
+    ///
+    /// Our writable mapping is replaced by a read-only one: the kernel refuses
+    /// to seal memory that still has writable mappings.
+    /// The memory is released if sealing fails.
+    pub fn seal(mut self) -> Result<SealedMemory, Error> {
+        unsafe {
+            let shm_size = self.data_size;
+            self.unmap();
+
+            if libc::fcntl(self.fd, F_ADD_SEALS, REQUIRED_SEALS | F_SEAL_SEAL) < 0 {
+                return Err(Error::SealFailed(io::Error::last_os_error()));
+            }
+
+            self.data_ptr = mmap_fd(self.fd, shm_size, PROT_READ, &self.options)?;
+            self.data_size = shm_size;
+        }
+        Ok(SealedMemory(self))
+    }
+
+    /// Fix the size of the memory for everyone, including us.
+    /// Only works on memory created with `anonymous`.
+    ///
+    /// Unlike `seal`, the content can still change and writable mappings stay valid.
+    /// Peers can't truncate the memory anymore, which would make our accesses fail (`SIGBUS`).
+    pub fn seal_size(&self) -> Result<(), Error> {
+        if unsafe { libc::fcntl(self.fd, F_ADD_SEALS, F_SEAL_SHRINK | F_SEAL_GROW | F_SEAL_SEAL) } < 0 {
+            return Err(Error::SealFailed(io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    /// Change the size of the underlying object, then remap it.
+    /// Peers must call `remap` to see the new size.
+    ///
+    /// # Safety
+    /// All the slices previously obtained from this memory become invalid
+    pub unsafe fn resize(&mut self, new_size: usize) -> Result<(), Error> {
+        if new_size < self.data_size {
+            return Err(Error::CannotShrink);
+        }
+        if libc::ftruncate(self.fd, new_size as off_t) < 0 {
+            return Err(Error::TruncateFailed(io::Error::last_os_error()));
+        }
+        self.remap(new_size)
+    }
+
+    /// Current size of the underlying object, which may differ from our mapping
+    fn object_size(&self) -> Result<usize, Error> {
+        let mut stat: libc::stat = unsafe { mem::zeroed() };
+        if unsafe { libc::fstat(self.fd, &mut stat) } < 0 {
+            return Err(Error::StatFailed(io::Error::last_os_error()));
+        }
+        Ok(stat.st_size as usize)
+    }
+
+    /// Change the size of our mapping, without touching the underlying object.
+    /// The mapping may move in memory.
+    ///
+    /// # Safety
+    /// All the slices previously obtained from this memory become invalid.
+    /// The object must be at least `new_size` bytes large.
+    pub unsafe fn remap(&mut self, new_size: usize) -> Result<(), Error> {
+        if self.data_size == 0 || new_size == 0 {
+            // mremap doesn't deal with empty mappings
+            self.unmap();
+            self.data_ptr = mmap_fd(self.fd, new_size, PROT_READ | PROT_WRITE, &self.options)?;
+        }
+        else {
+            let addr = libc::mremap(self.data_ptr.cast(), self.data_size, new_size, MREMAP_MAYMOVE);
+            if addr == MAP_FAILED {
+                return Err(Error::MapFailed(io::Error::last_os_error()));
+            }
+            // Keep the size in sync with the pointer, even if what follows fails
+            self.data_ptr = addr.cast();
+            self.data_size = new_size;
+            // The new pages don't inherit everything from the old mapping
+            self.options.after_map(self.data_ptr, new_size)?;
+        }
+        self.data_size = new_size;
+        Ok(())
+    }
+
+    unsafe fn unmap(&mut self) {
+        if self.data_size > 0 {
+            libc::munmap(self.data_ptr.cast(), self.data_size);
+        }
+        self.data_ptr = ptr::NonNull::dangling().as_ptr();
+        self.data_size = 0;
+    }
+
+    /// The name of the shm object, without the null terminator.
+    /// Returns `None` for anonymous memory.
+    pub fn name(&self) -> Option<&str> {
+        self.name
+            .as_deref()
+            .map(|name| name.trim_end_matches('\0'))
+    }
+
+    /// Number of bytes in the mapping
+    pub fn len(&self) -> usize {
+        self.data_size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data_size == 0
+    }
+
+    /// Remove the name of the object from the system.
+    /// The memory stays mapped until all the processes drop their `SharedMemory`.
+    /// Anonymous memory has no name, this is then a no-op.
+    ///
+    /// Only one of the processes sharing this object should call this,
+    /// once all of them have opened it.
+    pub fn unlink(&self) -> io::Result<()> {
+        let Some(name) = &self.name else {
+            return Ok(());
+        };
+        let c_name = name.as_ptr() as *const c_char;
+        match unsafe { libc::shm_unlink(c_name) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    /// # Safety
+    /// Another process may be writing to the memory concurrently
     pub unsafe fn as_slice(&self) -> &[u8] {
         slice::from_raw_parts(self.data_ptr, self.data_size)
     }
 
+    /// # Safety
+    /// Another process may be accessing the memory concurrently
     pub unsafe fn as_slice_mut(&mut self) -> &mut [u8] {
         slice::from_raw_parts_mut(self.data_ptr, self.data_size)
     }
 }
 
+impl AsFd for SharedMemory {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        unsafe { BorrowedFd::borrow_raw(self.fd) }
+    }
+}
+
+impl AsRawFd for SharedMemory {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
 impl Drop for SharedMemory {
     fn drop(&mut self) {
         unsafe {
-            // TODO call shm_unlink?
+            self.unmap();
             libc::close(self.fd);
         }
     }
 }
+
+/// mmap refuses empty mappings, we hand out a dangling pointer in that case
+unsafe fn mmap_fd(fd: Fd, shm_size: usize, prot: c_int, options: &Builder) -> Result<*mut u8, Error> {
+    if shm_size == 0 {
+        return Ok(ptr::NonNull::dangling().as_ptr());
+    }
+
+    let null = ptr::null_mut();
+    let addr = libc::mmap(null, shm_size, prot, options.mmap_flags(), fd, 0);
+    if addr == MAP_FAILED {
+        let err = io::Error::last_os_error();
+        return match options.huge_pages {
+            HugePages::Explicit => Err(Error::HugePagesUnavailable(err)),
+            _                   => Err(Error::MapFailed(err)),
+        };
+    }
+
+    let addr = addr.cast();
+    if let Err(e) = options.after_map(addr, shm_size) {
+        libc::munmap(addr.cast(), shm_size);
+        return Err(e);
+    }
+    Ok(addr)
+}
+
+/* Sealed memory */
+
+/// Seals guaranteeing that the content of the memory never changes
+const REQUIRED_SEALS: c_int = F_SEAL_WRITE | F_SEAL_SHRINK | F_SEAL_GROW;
+
+/// Shared memory that nobody can modify or resize anymore.
+/// This is enforced by the kernel, so reading from it is safe
+/// even if the process that created it doesn't behave.
+pub struct SealedMemory(SharedMemory);
+
+// The mapping is read-only and its content can't change: nothing to synchronize
+unsafe impl Send for SealedMemory {}
+unsafe impl Sync for SealedMemory {}
+
+impl SealedMemory {
+    /// Map sealed memory received from another process.
+    /// Fails if the seals are missing, see `SharedMemory::seal`.
+    pub fn from_fd(fd: OwnedFd) -> Result<Self, Error> {
+        let seals = unsafe { libc::fcntl(fd.as_raw_fd(), F_GET_SEALS) };
+        if seals < 0 {
+            return Err(Error::SealFailed(io::Error::last_os_error()));
+        }
+        if seals & REQUIRED_SEALS != REQUIRED_SEALS {
+            return Err(Error::NotSealed(seals));
+        }
+
+        unsafe {
+            SharedMemory::map_whole(fd.into_raw_fd(), PROT_READ, Builder::new())
+                .map(SealedMemory)
+        }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        // Safety: the seals prevent any concurrent modification
+        unsafe { self.0.as_slice() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl AsFd for SealedMemory {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.0.as_fd()
+    }
+}
+
+/* Mapped files */
+
+/// Read-only mapping of a regular file, e.g. received from another process.
+/// The pages are those of the page cache: nothing is copied.
+///
+/// Unlike `SealedMemory`, nothing prevents the owner of the file from changing it
+/// while it is mapped.
+pub struct MappedFile(SharedMemory);
+
+impl MappedFile {
+    /// Map the whole file, with its current size.
+    ///
+    /// # Safety
+    /// The file must not be truncated while mapped:
+    /// accessing pages past its new end raises `SIGBUS`
+    pub unsafe fn from_fd(fd: OwnedFd) -> Result<Self, Error> {
+        SharedMemory::map_whole(fd.into_raw_fd(), PROT_READ, Builder::new())
+            .map(MappedFile)
+    }
+
+    /// # Safety
+    /// The content can change under our feet if the file is written to
+    pub unsafe fn as_slice(&self) -> &[u8] {
+        self.0.as_slice()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl AsFd for MappedFile {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.0.as_fd()
+    }
+}
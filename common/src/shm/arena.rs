@@ -0,0 +1,231 @@
+//! Carve typed objects out of a single shared memory mapping
+//!
+//! Allocations are identified by their offset from the start of the mapping,
+//! never by their address: each process maps the memory at a different place.
+//! Offsets can themselves be stored in the shared memory, typically in a control
+//! block allocated first, so that peers can find the rest of the layout.
+
+use std::marker::PhantomData;
+use std::mem::{align_of, size_of};
+use std::{ptr, slice};
+use std::sync::atomic::{AtomicI32, AtomicI64, AtomicU32, AtomicU64, AtomicU8};
+
+use super::{Error, SharedMemory};
+
+/// Types that can live in shared memory.
+///
+/// # Safety
+/// The type must be `#[repr(C)]` (or a primitive), valid for any bit pattern
+/// including all zeroes, and must not contain pointers or references.
+pub unsafe trait ShmSafe {}
+
+unsafe impl ShmSafe for u8 {}
+unsafe impl ShmSafe for u16 {}
+unsafe impl ShmSafe for u32 {}
+unsafe impl ShmSafe for u64 {}
+unsafe impl ShmSafe for i8 {}
+unsafe impl ShmSafe for i16 {}
+unsafe impl ShmSafe for i32 {}
+unsafe impl ShmSafe for i64 {}
+unsafe impl ShmSafe for AtomicU8 {}
+unsafe impl ShmSafe for AtomicU32 {}
+unsafe impl ShmSafe for AtomicU64 {}
+unsafe impl ShmSafe for AtomicI32 {}
+unsafe impl ShmSafe for AtomicI64 {}
+unsafe impl<T: ShmSafe, const N: usize> ShmSafe for [T; N] {}
+unsafe impl<T> ShmSafe for Offset<T> {}
+unsafe impl<T> ShmSafe for SliceOffset<T> {}
+
+/// Mappings are page aligned: we can't guarantee larger alignments
+const MAX_ALIGN: usize = 4096;
+
+/// Position of a `T` inside a mapping
+#[repr(C)]
+#[derive(Debug)]
+pub struct Offset<T> {
+    offset:  u64,
+    _marker: PhantomData<T>,
+}
+
+/// Position of `len` consecutive `T`s inside a mapping
+#[repr(C)]
+#[derive(Debug)]
+pub struct SliceOffset<T> {
+    offset:  u64,
+    len:     u64,
+    _marker: PhantomData<T>,
+}
+
+// derive(Clone, Copy) would require T: Copy
+impl<T> Clone for Offset<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Offset<T> {}
+
+impl<T> Clone for SliceOffset<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for SliceOffset<T> {}
+
+impl<T> Offset<T> {
+    pub fn new(offset: usize) -> Self {
+        Self {
+            offset:  offset as u64,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn get(&self) -> usize {
+        self.offset as usize
+    }
+}
+
+impl<T> SliceOffset<T> {
+    pub fn new(offset: usize, len: usize) -> Self {
+        Self {
+            offset:  offset as u64,
+            len:     len as u64,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn get(&self) -> usize {
+        self.offset as usize
+    }
+
+    /// Number of elements
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// Bump allocator over a mapping.
+/// Allocated memory is never reused, and the arena itself is local to the process
+/// that sets the layout up: peers only need the offsets.
+pub struct Arena<'a> {
+    shm:  &'a SharedMemory,
+    used: usize,
+}
+
+impl<'a> Arena<'a> {
+    /// Allocate from the start of the mapping
+    pub fn new(shm: &'a SharedMemory) -> Self {
+        Self::starting_at(shm, 0)
+    }
+
+    /// Allocate after the first `reserved` bytes of the mapping
+    pub fn starting_at(shm: &'a SharedMemory, reserved: usize) -> Self {
+        Self {
+            shm,
+            used: reserved,
+        }
+    }
+
+    /// Number of bytes handed out so far, including padding
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    /// Reserve room for a `T`.
+    /// The memory keeps its current content, which is zeroes for fresh mappings.
+    pub fn alloc<T: ShmSafe>(&mut self) -> Result<Offset<T>, Error> {
+        let offset = self.alloc_raw(size_of::<T>(), align_of::<T>())?;
+        Ok(Offset::new(offset))
+    }
+
+    /// Reserve room for `len` consecutive `T`s
+    pub fn alloc_slice<T: ShmSafe>(&mut self, len: usize) -> Result<SliceOffset<T>, Error> {
+        let byte_len = size_of::<T>()
+                        .checked_mul(len)
+                        .ok_or(Error::OutOfBounds)?;
+        let offset = self.alloc_raw(byte_len, align_of::<T>())?;
+        Ok(SliceOffset::new(offset, len))
+    }
+
+    /// Reserve `len` bytes aligned on `align`, e.g. a cache line for a stream ring
+    pub fn alloc_bytes(&mut self, len: usize, align: usize) -> Result<SliceOffset<u8>, Error> {
+        let offset = self.alloc_raw(len, align)?;
+        Ok(SliceOffset::new(offset, len))
+    }
+
+    fn alloc_raw(&mut self, len: usize, align: usize) -> Result<usize, Error> {
+        if !align.is_power_of_two() || align > MAX_ALIGN {
+            return Err(Error::Misaligned);
+        }
+
+        let start = self.used
+                        .checked_next_multiple_of(align)
+                        .ok_or(Error::OutOfBounds)?;
+        let end = start
+                    .checked_add(len)
+                    .ok_or(Error::OutOfBounds)?;
+        if end > self.shm.len() {
+            return Err(Error::NotLargeEnough(end));
+        }
+        self.used = end;
+        Ok(start)
+    }
+}
+
+impl SharedMemory {
+    /// Validate that `[offset, offset + len)` fits in the mapping with the right alignment
+    fn checked_ptr(&self, offset: usize, len: usize, align: usize) -> Result<*mut u8, Error> {
+        if align > MAX_ALIGN || !offset.is_multiple_of(align) {
+            return Err(Error::Misaligned);
+        }
+        match offset.checked_add(len) {
+            Some(end) if end <= self.data_size => {
+                // Empty mappings use a dangling pointer, don't offset it
+                match self.data_size {
+                    0 => Ok(ptr::without_provenance_mut(align)),
+                    _ => Ok(unsafe { self.data_ptr.add(offset) }),
+                }
+            }
+            _ => Err(Error::OutOfBounds),
+        }
+    }
+
+    /// # Safety
+    /// Another process may be writing to the object concurrently
+    pub unsafe fn view<T: ShmSafe>(&self, offset: Offset<T>) -> Result<&T, Error> {
+        self.checked_ptr(offset.get(), size_of::<T>(), align_of::<T>())
+            .map(|ptr| &*ptr.cast())
+    }
+
+    /// # Safety
+    /// Another process may be accessing the object concurrently
+    pub unsafe fn view_mut<T: ShmSafe>(&mut self, offset: Offset<T>) -> Result<&mut T, Error> {
+        self.checked_ptr(offset.get(), size_of::<T>(), align_of::<T>())
+            .map(|ptr| &mut *ptr.cast())
+    }
+
+    /// # Safety
+    /// Another process may be writing to the slice concurrently
+    pub unsafe fn view_slice<T: ShmSafe>(&self, offset: SliceOffset<T>) -> Result<&[T], Error> {
+        let byte_len = size_of::<T>()
+                        .checked_mul(offset.len())
+                        .ok_or(Error::OutOfBounds)?;
+        self.checked_ptr(offset.get(), byte_len, align_of::<T>())
+            .map(|ptr| slice::from_raw_parts(ptr.cast(), offset.len()))
+    }
+
+    /// # Safety
+    /// Another process may be accessing the slice concurrently
+    pub unsafe fn view_slice_mut<T: ShmSafe>(&mut self, offset: SliceOffset<T>) -> Result<&mut [T], Error> {
+        let byte_len = size_of::<T>()
+                        .checked_mul(offset.len())
+                        .ok_or(Error::OutOfBounds)?;
+        self.checked_ptr(offset.get(), byte_len, align_of::<T>())
+            .map(|ptr| slice::from_raw_parts_mut(ptr.cast(), offset.len()))
+    }
+}
@@ -0,0 +1,182 @@
+//! Shared memory whose size can increase over time
+//!
+//! The first cache line of the region holds a header with the current data size
+//! and a generation counter. Growing the region increments the generation,
+//! which tells the peers that they need to remap their own view.
+//! Only one process should grow a given region.
+
+use std::mem::size_of;
+use std::os::fd::{AsFd, BorrowedFd};
+use std::slice;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::{Error, SharedMemory};
+
+#[repr(C)]
+struct GrowHeader {
+    generation: AtomicU64,
+    data_size:  AtomicU64,
+}
+
+// Keep the data portion aligned on a cache line
+const HEADER_SIZE: usize = 64;
+const _: () = assert!(size_of::<GrowHeader>() <= HEADER_SIZE);
+
+pub struct GrowableMemory {
+    shm: SharedMemory,
+    /// Generation of our current mapping
+    generation: u64,
+}
+
+impl GrowableMemory {
+    /// Set up the header of a fresh region.
+    /// The data portion is everything after the header.
+    ///
+    /// # Safety
+    /// No other process may be using the memory yet
+    pub unsafe fn init(shm: SharedMemory) -> Result<Self, Error> {
+        if shm.len() < HEADER_SIZE {
+            return Err(Error::NotLargeEnough(HEADER_SIZE));
+        }
+
+        let me = Self { shm, generation: 0 };
+        let header = me.header();
+        header.data_size.store((me.shm.len() - HEADER_SIZE) as u64, Ordering::Relaxed);
+        header.generation.store(0, Ordering::Release);
+        Ok(me)
+    }
+
+    /// Attach to a region set up by another process with `init`.
+    /// Remaps immediately if the region has grown since `shm` was mapped.
+    ///
+    /// # Safety
+    /// The region must have been set up with `init`
+    pub unsafe fn attach(shm: SharedMemory) -> Result<Self, Error> {
+        if shm.len() < HEADER_SIZE {
+            return Err(Error::NotLargeEnough(HEADER_SIZE));
+        }
+
+        // Force a remap in `refresh` if the sizes don't match
+        let mut me = Self { shm, generation: u64::MAX };
+        me.refresh()?;
+        Ok(me)
+    }
+
+    fn header(&self) -> &GrowHeader {
+        // The mapping is at least HEADER_SIZE bytes large and page aligned
+        unsafe { &*self.shm.data_ptr.cast() }
+    }
+
+    /// The generation of our view of the memory
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Whether another process grew the region since our last `refresh`
+    pub fn has_grown(&self) -> bool {
+        self.header().generation.load(Ordering::Acquire) != self.generation
+    }
+
+    /// Grow the data portion to `new_data_size` bytes and notify the peers.
+    ///
+    /// # Safety
+    /// All the slices previously obtained from this memory become invalid
+    pub unsafe fn grow(&mut self, new_data_size: usize) -> Result<(), Error> {
+        self.shm.resize(HEADER_SIZE + new_data_size)?;
+
+        // The object is already large enough when the peers see the new size
+        let header = self.header();
+        header.data_size.store(new_data_size as u64, Ordering::Relaxed);
+        self.generation = header.generation.fetch_add(1, Ordering::Release) + 1;
+        Ok(())
+    }
+
+    /// Remap our view if the region has grown.
+    /// Returns whether a remap happened.
+    /// Fails with `OutOfBounds` if the header announces more than the object holds.
+    ///
+    /// # Safety
+    /// All the slices previously obtained from this memory become invalid
+    pub unsafe fn refresh(&mut self) -> Result<bool, Error> {
+        let header = self.header();
+        let generation = header.generation.load(Ordering::Acquire);
+        if generation == self.generation {
+            return Ok(false);
+        }
+
+        // We may read the size of a newer generation: the object is at least that large.
+        // Any peer can write the header, so check that rather than trusting it:
+        // mapping past the end of the object would fail on first access (SIGBUS)
+        let data_size = header.data_size.load(Ordering::Relaxed);
+        let new_size = usize::try_from(data_size)
+                        .ok()
+                        .and_then(|data_size| data_size.checked_add(HEADER_SIZE))
+                        .ok_or(Error::OutOfBounds)?;
+        if new_size > self.shm.object_size()? {
+            return Err(Error::OutOfBounds);
+        }
+        self.shm.remap(new_size)?;
+        self.generation = generation;
+        Ok(true)
+    }
+
+    /// Size of the data portion in our current view
+    pub fn len(&self) -> usize {
+        self.shm.len() - HEADER_SIZE
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// # Safety
+    /// Another process may be writing to the memory concurrently
+    pub unsafe fn as_slice(&self) -> &[u8] {
+        slice::from_raw_parts(self.shm.data_ptr.add(HEADER_SIZE), self.len())
+    }
+
+    /// # Safety
+    /// Another process may be accessing the memory concurrently
+    pub unsafe fn as_slice_mut(&mut self) -> &mut [u8] {
+        slice::from_raw_parts_mut(self.shm.data_ptr.add(HEADER_SIZE), self.len())
+    }
+
+    pub fn into_inner(self) -> SharedMemory {
+        self.shm
+    }
+}
+
+impl AsFd for GrowableMemory {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.shm.as_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn forge_size(memory: &GrowableMemory, data_size: u64) {
+        let header = memory.header();
+        header.data_size.store(data_size, Ordering::Relaxed);
+        header.generation.fetch_add(1, Ordering::Release);
+    }
+
+    #[test]
+    fn refresh_rejects_sizes_past_the_object() {
+        unsafe {
+            let shm = SharedMemory::anonymous("growable-test\0", 4096).unwrap();
+            let mut memory = GrowableMemory::init(shm).unwrap();
+
+            forge_size(&memory, 4096);
+            assert!(matches!(memory.refresh(), Err(Error::OutOfBounds)));
+            forge_size(&memory, u64::MAX);
+            assert!(matches!(memory.refresh(), Err(Error::OutOfBounds)));
+            assert_eq!(memory.len(), 4096 - HEADER_SIZE);
+
+            forge_size(&memory, 100);
+            assert!(memory.refresh().unwrap());
+            assert_eq!(memory.len(), 100);
+        }
+    }
+}
@@ -1,13 +1,13 @@
-// TODO make the following doc be for the module itself
-/// An inter-process single reader single writer ring buffer over shared memory
+//! An inter-process single reader single writer ring buffer over shared memory
 
 /* Common */
 
 use std::cmp::min;
+use std::{error, fmt};
 use std::mem::size_of;
 use std::slice;
 use std::sync::atomic::{self, AtomicU64};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 /// A single row in the header of the shared memory area
 #[repr(C)]
@@ -22,10 +22,6 @@ impl ShmUsefulRow {
         &(*me).status
     }
 
-    unsafe fn atomic_length<'a>(me: *mut Self) -> &'a AtomicU64 {
-        &(*me).length
-    }
-
     unsafe fn atomic_count<'a>(me: *mut Self) -> &'a AtomicU64 {
         &(*me).count
     }
@@ -51,6 +47,7 @@ struct ShmHeaderFormat {
 }
 
 // TODO move around
+#[derive(Debug)]
 pub enum Error {
     SharedMemoryNotLargeEnough,
     HandshakeFailed,
@@ -59,6 +56,33 @@ pub enum Error {
     InvalidStatus(u64),
     /// Must call `prepare_memory` before attempting to create streams
     MemoryNotPrepared,
+    /// The partner made no progress within the timeout
+    TimedOut,
+    /// The partner published a count that went backwards or past what the ring allows.
+    /// The shared memory is writable by both sides, so this is never trusted.
+    CorruptCount(u64),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::SharedMemoryNotLargeEnough => write!(f, "shared memory is too small for a stream"),
+            Error::HandshakeFailed            => write!(f, "stream handshake failed"),
+            Error::PartnerDisconnected        => write!(f, "the other end of the stream disconnected"),
+            Error::InvalidStatus(status)      => write!(f, "invalid stream status {status}"),
+            Error::MemoryNotPrepared          => write!(f, "stream memory was not prepared"),
+            Error::TimedOut                   => write!(f, "the other end of the stream stopped responding"),
+            Error::CorruptCount(count)        => write!(f, "the other end of the stream published an invalid count ({count})"),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<MemNotBigEnough> for Error {
+    fn from(_: MemNotBigEnough) -> Self {
+        Error::SharedMemoryNotLargeEnough
+    }
 }
 
 impl ShmHeaderFormat {
@@ -70,31 +94,21 @@ impl ShmHeaderFormat {
             Err(Error::SharedMemoryNotLargeEnough)
         }
     }
-
-    unsafe fn reader_ptr(me: *mut Self) -> *mut ShmUsefulRow {
-        &mut (*me).reader_row.useful as *mut _
-    }
-
-    unsafe fn writer_ptr(me: *mut Self) -> *mut ShmUsefulRow {
-        &mut (*me).writer_row.useful as *mut _
-    }
-
-    unsafe fn handshake_channel<'a>(me: *mut Self) -> &'a AtomicU64 {
-        let writer_row = Self::writer_ptr(me);
-        ShmUsefulRow::atomic_status(writer_row)
-    }
 }
 
-/* Handshake protocol */
+/* Connection status */
 
 #[derive(Debug, PartialEq, Eq)]
 enum Status {
     NotConnected,
     Connected,
+    /// Done with the stream, e.g. the writer wrote everything
+    Closed,
 }
 
 const NOT_CONNECTED_STATUS: u64 = 0;
 const CONNECTED_STATUS:     u64 = 1;
+const CLOSED_STATUS:        u64 = 2;
 
 impl TryFrom<u64> for Status {
     type Error = Error;
@@ -103,6 +117,7 @@ impl TryFrom<u64> for Status {
         match value {
             NOT_CONNECTED_STATUS => Ok(Status::NotConnected),
             CONNECTED_STATUS     => Ok(Status::Connected),
+            CLOSED_STATUS        => Ok(Status::Closed),
             _                    => Err(Error::InvalidStatus(value)),
         }
     }
@@ -113,68 +128,16 @@ impl From<Status> for u64 {
         match value {
             Status::NotConnected => NOT_CONNECTED_STATUS,
             Status::Connected    => CONNECTED_STATUS,
+            Status::Closed       => CLOSED_STATUS,
         }
     }
 }
 
-const WRITER_READY_STATUS: u64 = 0;
-const READER_ALSO_READY_STATUS: u64 = 1;
-const WRITING_STATUS: u64 = 2;
-const READING_STATUS: u64 = 3;
-const ABORT_STATUS: u64 = 4;
-
-// TODO this function requires a timeout
-unsafe fn writer_handshake(header: *mut ShmHeaderFormat, length: usize) -> Result<(), Error> {
-    // Step 1: set up our side
-    init_row(ShmHeaderFormat::writer_ptr(header), length);
-
-    // Step 2: write "READY"
-    let handshake_channel = ShmHeaderFormat::handshake_channel(header);
-    handshake_channel.store(WRITER_READY_STATUS, ATOMIC_ORDER);
-
-    let abort = || {
-        handshake_channel.store(ABORT_STATUS, ATOMIC_ORDER);
-        Err(Error::HandshakeFailed)
-    };
-
-    // Step 3: wait for "READER_ALSO_READY"
-    let handhsake_value = wait_for_change(handshake_channel, READER_ALSO_READY_STATUS);
-    if handhsake_value != READER_ALSO_READY_STATUS {
-        // Abort the handshake
-        return abort();
-    }
-
-    // Step 4: validate the reader's length field
-    let reader_row = ShmHeaderFormat::reader_ptr(header);
-    let reader_length = ShmUsefulRow::atomic_length(reader_row).load(ATOMIC_ORDER);
-    if reader_length != length as u64 {
-        // We don't agree with the reader on the length of the shared memory
-        // Abort the handshake
-        return abort();
-    }
-
-    // Step 5: write "WRITING"
-    match handshake_channel.compare_exchange(READER_ALSO_READY_STATUS, WRITING_STATUS, ATOMIC_ORDER, ATOMIC_ORDER) {
-        Ok(_) => Ok(()), // handshake successful
-        Err(_) => {
-            // Someone wrote something in the middle of our handshake
-            // Fail the handshake
-            abort()
-        }
-    }
-}
-
-const MAGIC_NUMBER: u64 = 0xbabe101ebabe101e;
-const ATOMIC_ORDER: atomic::Ordering = atomic::Ordering::Relaxed;
+/// The counts publish the data in the ring: they need at least release/acquire
+const ATOMIC_ORDER: atomic::Ordering = atomic::Ordering::SeqCst;
 
-/// Set up the non-status fields of the given row header
-/// This must be performed before starting the handshake
-unsafe fn init_row(row: *mut ShmUsefulRow, length: usize) {
-    // TODO make this into a compile-time constant
-    assert!(size_of::<usize>() <= size_of::<u64>());
-    ShmUsefulRow::atomic_length(row).store(length as u64, ATOMIC_ORDER);
-    ShmUsefulRow::atomic_count(row).store(0, ATOMIC_ORDER);
-}
+/// Longest sleep between two checks, bounds the latency once the partner wakes up
+const MAX_WAIT: Duration = Duration::from_millis(1);
 
 struct ExpWait {
     curr_wait: Duration
@@ -190,35 +153,10 @@ impl ExpWait {
     fn wait(&mut self) {
         // TODO check the implementation of Rust channels to know how they wake up
         std::thread::sleep(self.curr_wait);
-        self.curr_wait = self.curr_wait.saturating_mul(2);
-    }
-}
-
-/// Wait for any concurrent change at the given memory location.
-/// Returns early if some expected value is found.
-fn wait_for_change(channel: &AtomicU64, early_stop: u64) -> u64 {
-    let initial_value = channel.load(ATOMIC_ORDER);
-
-    if initial_value == early_stop {
-        return initial_value;
-    }
-
-    // Wait for any change on their side.
-    // We'll return the first value that change, whatever it is.
-    let mut latest_value = channel.load(ATOMIC_ORDER);
-    let mut waiter = ExpWait::new();
-    while latest_value == initial_value {
-        // TODO introduce a timeout
-        waiter.wait();
-        latest_value = channel.load(ATOMIC_ORDER);
+        self.curr_wait = min(self.curr_wait.saturating_mul(2), MAX_WAIT);
     }
-
-    return latest_value;
 }
 
-
-
-
 /* Common header row API */
 
 // Write-only, never need to read
@@ -231,6 +169,11 @@ impl MyRow {
         ShmUsefulRow::atomic_count(self.row_ptr)
             .store(tot_count, ATOMIC_ORDER);
     }
+
+    unsafe fn write_status(&mut self, status: Status) {
+        ShmUsefulRow::atomic_status(self.row_ptr)
+            .store(status.into(), ATOMIC_ORDER);
+    }
 }
 
 impl From<*mut ShmUsefulRow> for MyRow {
@@ -247,16 +190,6 @@ struct PartnerRow {
 }
 
 impl PartnerRow {
-    unsafe fn read_count(&self) -> Result<u64, Error> {
-        let partner_pos = ShmUsefulRow::atomic_count(self.row_ptr)
-                                .load(ATOMIC_ORDER);
-        // Check the status after reading the position
-        // If the partner aborts in between the two reads,
-        // the pos value can be complete garbage
-        self.check_status()
-            .map(|_| partner_pos)
-    }
-
     unsafe fn check_status(&self) -> Result<(), Error> {
         let status: Status = ShmUsefulRow::atomic_status(self.row_ptr)
                                 .load(ATOMIC_ORDER)
@@ -269,17 +202,29 @@ impl PartnerRow {
         }
     }
 
-    unsafe fn wait_for_count_change(&self, known_count: u64) -> Result<u64, Error> {
-        // Wait for any change on their side.
-        // We'll return the first value that change, whatever it is.
-        let mut curr_count = self.read_count()?;
+    /// Wait for any change on their side, and return the new count.
+    /// Progress made before the partner disconnected is still reported.
+    /// The new count must lie in `known_count..=max_count`, anything else is an error.
+    unsafe fn wait_for_count_change(&self, known_count: u64, max_count: u64, timeout: Option<Duration>) -> Result<u64, Error> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
         let mut waiter = ExpWait::new();
-            while curr_count == known_count {
-            // TODO introduce a timeout
+        loop {
+            // Status first: the partner updates its count before closing
+            let status = self.check_status();
+            let curr_count = ShmUsefulRow::atomic_count(self.row_ptr).load(ATOMIC_ORDER);
+            if curr_count != known_count {
+                if !(known_count..=max_count).contains(&curr_count) {
+                    return Err(Error::CorruptCount(curr_count));
+                }
+                return Ok(curr_count);
+            }
+            status?;
+
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Err(Error::TimedOut);
+            }
             waiter.wait();
-            curr_count = self.read_count()?;
         }
-        Ok(curr_count)
     }
 }
 
@@ -301,12 +246,16 @@ pub struct StreamWriter {
     tot_bytes_written:     u64,
     cached_tot_bytes_read: u64,
     partner_row:           PartnerRow,
-    my_row:                MyRow
+    my_row:                MyRow,
+    /// How long to wait for the reader before giving up, forever if `None`
+    timeout:               Option<Duration>,
 }
 
 // Note: implementing the io::Write trait would be deceiving,
 // as all our APIs are unsafe
 impl StreamWriter {
+    /// # Safety
+    /// The underlying shared memory must still be mapped
     pub unsafe fn write_all(&mut self, buf: &[u8]) -> Result<(), Error> {
         let mut still_to_write = buf;
         while !still_to_write.is_empty() {
@@ -333,15 +282,11 @@ impl StreamWriter {
     // Only reads info from cache
     unsafe fn contiguous_write_slice_non_blocking(&mut self) -> &mut [u8] {
         let slice_start = (self.tot_bytes_written % self.data_len as u64) as usize;
-        let slice_end = (self.tot_bytes_written - self.cached_tot_bytes_read) as usize;
+        let free_len = self.data_len - (self.tot_bytes_written - self.cached_tot_bytes_read) as usize;
+        // Stop at the end of the ring, the rest is for the next call
+        let slice_len = min(free_len, self.data_len - slice_start);
         let start_ptr = self.anchor_ptr.add(slice_start);
-        slice::from_raw_parts_mut(start_ptr, slice_end - slice_start)
-    }
-
-    // May fail if the reader is no longer reading
-    unsafe fn update_cache(&mut self) -> Result<(), Error> {
-        self.cached_tot_bytes_read = self.partner_row.read_count()?;
-        Ok(())
+        slice::from_raw_parts_mut(start_ptr, slice_len)
     }
 
     // Notifies that a certain number of bytes have been written
@@ -357,24 +302,118 @@ impl StreamWriter {
 
     /// Can fail if the reader disconnected
     unsafe fn wait_for_write_space(&mut self) -> Result<(), Error> {
-        // TODO implement a timeout
         // TODO implement an adaptive wait based on reader/writer speed
         // TODO introduce a minimum write space
-        self.cached_tot_bytes_read = self.partner_row.wait_for_count_change(self.cached_tot_bytes_read)?;
+        // The reader can't have read more than we wrote
+        let known_count = self.cached_tot_bytes_read;
+        self.cached_tot_bytes_read = self.partner_row.wait_for_count_change(known_count, self.tot_bytes_written, self.timeout)?;
         Ok(())
     }
+
+    /// Tell the reader that nothing more will be written.
+    /// It can still read what was written so far.
+    ///
+    /// # Safety
+    /// The underlying shared memory must still be mapped
+    pub unsafe fn close(mut self) {
+        self.my_row.write_status(Status::Closed);
+    }
 }
 
 /* Reader */
 
+pub struct StreamReader {
+    /// Where the data portion starts
+    anchor_ptr:               *mut u8,
+    /// Length of the data portion
+    data_len:                 usize,
+    tot_bytes_read:           u64,
+    cached_tot_bytes_written: u64,
+    partner_row:              PartnerRow,
+    my_row:                   MyRow,
+    /// How long to wait for the writer before giving up, forever if `None`
+    timeout:                  Option<Duration>,
+}
+
+// Same as the writer, no io::Read
+impl StreamReader {
+    /// Block until some data is available, and copy as much as possible into `buf`.
+    /// Returns 0 once the writer closed the stream and everything was read.
+    ///
+    /// # Safety
+    /// The underlying shared memory must still be mapped
+    pub unsafe fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.tot_bytes_read == self.cached_tot_bytes_written {
+            // The writer can't be more than a full ring ahead of us
+            let max_count = self.tot_bytes_read + self.data_len as u64;
+            match self.partner_row.wait_for_count_change(self.tot_bytes_read, max_count, self.timeout) {
+                Ok(count) => self.cached_tot_bytes_written = count,
+                Err(Error::PartnerDisconnected) => return Ok(0),
+                Err(e) => return Err(e),
+            }
+        }
+
+        let read_from = self.contiguous_read_slice();
+        let read_len = min(read_from.len(), buf.len());
+        buf[..read_len].copy_from_slice(&read_from[..read_len]);
+        self.read_done(read_len);
+        Ok(read_len)
+    }
+
+    /// Fill the whole of `buf`.
+    /// Fails with `PartnerDisconnected` if the stream ends before.
+    ///
+    /// # Safety
+    /// The underlying shared memory must still be mapped
+    pub unsafe fn read_exact(&mut self, mut buf: &mut [u8]) -> Result<(), Error> {
+        while !buf.is_empty() {
+            match self.read(buf)? {
+                0 => return Err(Error::PartnerDisconnected),
+                n => buf = &mut buf[n..],
+            }
+        }
+        Ok(())
+    }
+
+    // Only reads info from cache
+    unsafe fn contiguous_read_slice(&self) -> &[u8] {
+        let slice_start = (self.tot_bytes_read % self.data_len as u64) as usize;
+        let available_len = (self.cached_tot_bytes_written - self.tot_bytes_read) as usize;
+        let slice_len = min(available_len, self.data_len - slice_start);
+        slice::from_raw_parts(self.anchor_ptr.add(slice_start), slice_len)
+    }
+
+    // Hands the bytes back to the writer
+    unsafe fn read_done(&mut self, byte_count: usize) {
+        self.tot_bytes_read += byte_count as u64;
+        self.my_row.write_tot_count(self.tot_bytes_read);
+    }
+
+    /// Tell the writer that we won't read anymore
+    ///
+    /// # Safety
+    /// The underlying shared memory must still be mapped
+    pub unsafe fn close(mut self) {
+        self.my_row.write_status(Status::Closed);
+    }
+}
+
 /* Builder */
 
-pub struct MemNotBigEnough(usize);
+/// Holds the minimum number of bytes required
+#[derive(Debug)]
+pub struct MemNotBigEnough(pub usize);
 
 const HEADER_SIZE: usize = size_of::<ShmHeaderFormat>();
 
 /// Warning: must only be done once
 /// Must be done before calling either new_writer or new_reader
+///
+/// # Safety
+/// `addr` must point to at least `mem_sz` writable bytes
 pub unsafe fn prepare_memory(addr: *mut u8, mem_sz: usize) -> Result<(), MemNotBigEnough> {
     if mem_sz >= HEADER_SIZE {
         // Rust's memset
@@ -389,7 +428,9 @@ pub unsafe fn prepare_memory(addr: *mut u8, mem_sz: usize) -> Result<(), MemNotB
 pub struct BuildWriter(StreamWriter);
 
 impl BuildWriter {
-    /// Memory must have been prepared with `prepare_memory`
+    /// # Safety
+    /// Memory must have been prepared with `prepare_memory`,
+    /// and `addr` must point to at least `mem_sz` writable bytes
     pub unsafe fn new(addr: *mut u8, mem_sz: usize) -> Result<Self, Error> {
         let header = ShmHeaderFormat::from_raw_mut(addr, mem_sz)?;
         let my_row = &mut header.writer_row.useful;
@@ -413,16 +454,25 @@ impl BuildWriter {
         my_row.status.store(Status::Connected.into(), ATOMIC_ORDER);
 
         let writer = StreamWriter {
-            anchor_ptr: addr,
+            anchor_ptr: addr.add(HEADER_SIZE),
             data_len: mem_sz - HEADER_SIZE,
             tot_bytes_written: 0,
             cached_tot_bytes_read: 0,
             partner_row: PartnerRow::from(&mut header.reader_row.useful as *mut _),
             my_row: MyRow::from(my_row as *mut _),
+            timeout: None,
         };
         Ok(BuildWriter(writer))
     }
 
+    /// Give up waiting for the reader after `timeout`, when connecting and writing
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.0.timeout = Some(timeout);
+        self
+    }
+
+    /// # Safety
+    /// The underlying shared memory must still be mapped
     pub unsafe fn is_ready(&self) -> Result<bool, Error> {
         // TODO validate the length
         match self.0.partner_row.check_status() {
@@ -432,17 +482,197 @@ impl BuildWriter {
         }
     }
 
+    /// # Safety
+    /// The underlying shared memory must still be mapped
     pub unsafe fn blocking_into(self) -> Result<StreamWriter, Error> {
         self.wait_until_connected()?;
         Ok(self.0)
     }
 
     unsafe fn wait_until_connected(&self) -> Result<(), Error> {
-        let mut waiter = ExpWait::new();
-        while !self.is_ready()? {
-            // TODO introduce a timeout
-            waiter.wait();
+        wait_until_connected(&self.0.partner_row, self.0.timeout)
+    }
+}
+
+pub struct BuildReader(StreamReader);
+
+impl BuildReader {
+    /// # Safety
+    /// Memory must have been prepared with `prepare_memory`,
+    /// and `addr` must point to at least `mem_sz` writable bytes
+    pub unsafe fn new(addr: *mut u8, mem_sz: usize) -> Result<Self, Error> {
+        let header = ShmHeaderFormat::from_raw_mut(addr, mem_sz)?;
+        let my_row = &mut header.reader_row.useful;
+
+        // Validate that memory is cleared out
+        if my_row.count.load(ATOMIC_ORDER) != 0
+            || my_row.length.load(ATOMIC_ORDER) != 0
+            || my_row.status.load(ATOMIC_ORDER) != 0
+        {
+            return Err(Error::MemoryNotPrepared);
+        }
+
+        // The writer may already be there, check that we agree on the size
+        let writer_length = header.writer_row.useful.length.load(ATOMIC_ORDER);
+        if writer_length != 0 && writer_length != mem_sz as u64 {
+            return Err(Error::HandshakeFailed);
+        }
+
+        // Same order as the writer
+        my_row.length.store(mem_sz as u64, ATOMIC_ORDER);
+        my_row.status.store(Status::Connected.into(), ATOMIC_ORDER);
+
+        let reader = StreamReader {
+            anchor_ptr: addr.add(HEADER_SIZE),
+            data_len: mem_sz - HEADER_SIZE,
+            tot_bytes_read: 0,
+            cached_tot_bytes_written: 0,
+            partner_row: PartnerRow::from(&mut header.writer_row.useful as *mut _),
+            my_row: MyRow::from(my_row as *mut _),
+            timeout: None,
+        };
+        Ok(BuildReader(reader))
+    }
+
+    /// Give up waiting for the writer after `timeout`, when connecting and reading
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.0.timeout = Some(timeout);
+        self
+    }
+
+    /// # Safety
+    /// The underlying shared memory must still be mapped
+    pub unsafe fn is_ready(&self) -> Result<bool, Error> {
+        match self.0.partner_row.check_status() {
+            Ok(()) => Ok(true),
+            Err(Error::PartnerDisconnected) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// # Safety
+    /// The underlying shared memory must still be mapped
+    pub unsafe fn blocking_into(self) -> Result<StreamReader, Error> {
+        wait_until_connected(&self.0.partner_row, self.0.timeout)?;
+        Ok(self.0)
+    }
+}
+
+unsafe fn wait_until_connected(partner_row: &PartnerRow, timeout: Option<Duration>) -> Result<(), Error> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut waiter = ExpWait::new();
+    loop {
+        match partner_row.check_status() {
+            Ok(()) => return Ok(()),
+            Err(Error::PartnerDisconnected) => {},
+            Err(e) => return Err(e),
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(Error::TimedOut);
+        }
+        waiter.wait();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATA_LEN: usize = 16;
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    /// Heap memory standing in for shared memory, aligned for the atomics
+    struct Ring {
+        memory: Vec<u64>,
+    }
+
+    impl Ring {
+        fn new() -> Self {
+            let mut ring = Self {
+                memory: vec![0; (HEADER_SIZE + DATA_LEN) / size_of::<u64>()],
+            };
+            unsafe { prepare_memory(ring.addr(), ring.len()).unwrap() };
+            ring
+        }
+
+        fn addr(&mut self) -> *mut u8 {
+            self.memory.as_mut_ptr().cast()
+        }
+
+        fn len(&self) -> usize {
+            self.memory.len() * size_of::<u64>()
+        }
+
+        fn connect(&mut self) -> (StreamWriter, StreamReader) {
+            unsafe {
+                let writer = BuildWriter::new(self.addr(), self.len()).unwrap().with_timeout(TIMEOUT);
+                let reader = BuildReader::new(self.addr(), self.len()).unwrap().with_timeout(TIMEOUT);
+                (writer.blocking_into().unwrap(), reader.blocking_into().unwrap())
+            }
+        }
+
+        fn header(&mut self) -> &ShmHeaderFormat {
+            unsafe { &*self.addr().cast() }
+        }
+    }
+
+    #[test]
+    fn slices_stop_at_the_end_of_the_ring() {
+        let mut ring = Ring::new();
+        let (mut writer, mut reader) = ring.connect();
+        unsafe {
+            writer.write_all(&[1; 10]).unwrap();
+            let mut buf = [0; 10];
+            reader.read_exact(&mut buf).unwrap();
+
+            // Bytes 10 to 15 first, then 0 to 5
+            writer.wait_for_write_space().unwrap();
+            assert_eq!(writer.contiguous_write_slice_non_blocking().len(), DATA_LEN - 10);
+            let data: Vec<u8> = (0..12).collect();
+            writer.write_all(&data).unwrap();
+            assert_eq!(writer.contiguous_write_slice_non_blocking().len(), 4);
+
+            let mut buf = [0; 12];
+            assert_eq!(reader.read(&mut buf).unwrap(), DATA_LEN - 10);
+            reader.read_exact(&mut buf[DATA_LEN - 10..]).unwrap();
+            assert_eq!(&buf[..], &data[..]);
+        }
+    }
+
+    #[test]
+    fn writer_rejects_a_reader_count_past_the_written_bytes() {
+        let mut ring = Ring::new();
+        let (mut writer, _reader) = ring.connect();
+        unsafe {
+            writer.write_all(&[1; DATA_LEN]).unwrap();
+            ring.header().reader_row.useful.count.store(DATA_LEN as u64 + 1, ATOMIC_ORDER);
+            assert!(matches!(writer.write_all(&[1]), Err(Error::CorruptCount(count)) if count == DATA_LEN as u64 + 1));
+        }
+    }
+
+    #[test]
+    fn writer_rejects_a_reader_count_going_backwards() {
+        let mut ring = Ring::new();
+        let (mut writer, mut reader) = ring.connect();
+        unsafe {
+            writer.write_all(&[1; DATA_LEN]).unwrap();
+            let mut buf = [0; 8];
+            reader.read_exact(&mut buf).unwrap();
+            writer.write_all(&[1; 8]).unwrap();
+
+            ring.header().reader_row.useful.count.store(4, ATOMIC_ORDER);
+            assert!(matches!(writer.write_all(&[1]), Err(Error::CorruptCount(4))));
+        }
+    }
+
+    #[test]
+    fn reader_rejects_a_writer_count_past_the_ring() {
+        let mut ring = Ring::new();
+        let (_writer, mut reader) = ring.connect();
+        unsafe {
+            ring.header().writer_row.useful.count.store(DATA_LEN as u64 + 1, ATOMIC_ORDER);
+            let mut buf = [0; 4];
+            assert!(matches!(reader.read(&mut buf), Err(Error::CorruptCount(_))));
         }
-        Ok(())
     }
 }
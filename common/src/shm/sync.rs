@@ -0,0 +1,223 @@
+//! Lock and condition variable living inside shared memory
+//!
+//! Both are pthread primitives configured with `PTHREAD_PROCESS_SHARED`.
+//! The mutex is also robust: if a process dies while holding it,
+//! the next process to lock it gets it back and is told about it,
+//! see `ShmMutexGuard::inherited`.
+//!
+//! Allocate them with `arena::Arena`, then call `init` exactly once,
+//! before any other process gets access to the memory.
+
+use libc::{pthread_cond_t, pthread_condattr_t, pthread_mutex_t, pthread_mutexattr_t};
+use libc::{c_int, CLOCK_MONOTONIC, EOWNERDEAD, ENOTRECOVERABLE, ETIMEDOUT, PTHREAD_MUTEX_ROBUST, PTHREAD_PROCESS_SHARED};
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+use std::ops::{Deref, DerefMut};
+use std::time::Duration;
+use std::{io, mem};
+
+use super::arena::ShmSafe;
+use super::Error;
+
+/// Turn a pthread return code into a `Result`
+fn check(code: c_int, to_error: fn(io::Error) -> Error) -> Result<(), Error> {
+    match code {
+        0 => Ok(()),
+        _ => Err(to_error(io::Error::from_raw_os_error(code))),
+    }
+}
+
+/* Mutex */
+
+#[repr(C)]
+pub struct ShmMutex<T> {
+    raw:  UnsafeCell<pthread_mutex_t>,
+    data: UnsafeCell<T>,
+}
+
+// All zeroes is PTHREAD_MUTEX_INITIALIZER on Linux: still requires `init` to be shareable
+unsafe impl<T: ShmSafe> ShmSafe for ShmMutex<T> {}
+unsafe impl<T: Send> Send for ShmMutex<T> {}
+unsafe impl<T: Send> Sync for ShmMutex<T> {}
+
+/// Must be dropped by the thread that locked the mutex:
+/// a robust mutex refuses to be unlocked by anyone else
+pub struct ShmMutexGuard<'a, T> {
+    mutex:     &'a ShmMutex<T>,
+    inherited: bool,
+    /// Not `Send`, like `std::sync::MutexGuard`
+    _not_send: PhantomData<*const ()>,
+}
+
+// Sharing a reference doesn't move the unlock to another thread
+unsafe impl<T: Sync> Sync for ShmMutexGuard<'_, T> {}
+
+impl<T> ShmMutex<T> {
+    /// Set up the lock. The protected data is left as is.
+    ///
+    /// # Safety
+    /// Must be called exactly once, before any process uses the lock
+    pub unsafe fn init(&self) -> Result<(), Error> {
+        let mut attr: MaybeUninit<pthread_mutexattr_t> = MaybeUninit::uninit();
+        check(libc::pthread_mutexattr_init(attr.as_mut_ptr()), Error::MutexFailed)?;
+
+        let res = check(libc::pthread_mutexattr_setpshared(attr.as_mut_ptr(), PTHREAD_PROCESS_SHARED), Error::MutexFailed)
+            .and_then(|_| check(libc::pthread_mutexattr_setrobust(attr.as_mut_ptr(), PTHREAD_MUTEX_ROBUST), Error::MutexFailed))
+            .and_then(|_| check(libc::pthread_mutex_init(self.raw.get(), attr.as_ptr()), Error::MutexFailed));
+
+        libc::pthread_mutexattr_destroy(attr.as_mut_ptr());
+        res
+    }
+
+    /// Block until we own the lock.
+    /// Succeeds even if the previous owner died while holding the lock:
+    /// check `ShmMutexGuard::inherited` in that case, the data may be half-updated.
+    pub fn lock(&self) -> Result<ShmMutexGuard<'_, T>, Error> {
+        let code = unsafe { libc::pthread_mutex_lock(self.raw.get()) };
+        self.guard_from(code)
+    }
+
+    fn guard_from(&self, code: c_int) -> Result<ShmMutexGuard<'_, T>, Error> {
+        match code {
+            0 => Ok(ShmMutexGuard { mutex: self, inherited: false, _not_send: PhantomData }),
+            EOWNERDEAD => {
+                // We own the lock now, make it usable again for the others
+                let res = unsafe { libc::pthread_mutex_consistent(self.raw.get()) };
+                if let Err(e) = check(res, Error::MutexFailed) {
+                    // Don't keep the lock without a guard to release it
+                    unsafe { libc::pthread_mutex_unlock(self.raw.get()) };
+                    return Err(e);
+                }
+                Ok(ShmMutexGuard { mutex: self, inherited: true, _not_send: PhantomData })
+            }
+            ENOTRECOVERABLE => Err(Error::MutexNotRecoverable),
+            _ => Err(Error::MutexFailed(io::Error::from_raw_os_error(code))),
+        }
+    }
+
+    /// Take back the lock after a condition variable wait returned `code`.
+    /// The wait only fails with the lock still held, so release it in that case.
+    fn guard_after_wait(&self, code: c_int) -> Result<ShmMutexGuard<'_, T>, Error> {
+        match code {
+            0 | EOWNERDEAD | ENOTRECOVERABLE => self.guard_from(code),
+            _ => {
+                unsafe { libc::pthread_mutex_unlock(self.raw.get()) };
+                Err(Error::CondvarFailed(io::Error::from_raw_os_error(code)))
+            }
+        }
+    }
+}
+
+impl<T> ShmMutexGuard<'_, T> {
+    /// Whether the previous owner of the lock died without releasing it
+    pub fn inherited(&self) -> bool {
+        self.inherited
+    }
+}
+
+impl<T> Deref for ShmMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for ShmMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for ShmMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        let code = unsafe { libc::pthread_mutex_unlock(self.mutex.raw.get()) };
+        debug_assert_eq!(code, 0, "could not unlock a shared memory mutex");
+    }
+}
+
+/* Condition variable */
+
+#[repr(C)]
+pub struct ShmCondvar {
+    raw: UnsafeCell<pthread_cond_t>,
+}
+
+unsafe impl ShmSafe for ShmCondvar {}
+unsafe impl Send for ShmCondvar {}
+unsafe impl Sync for ShmCondvar {}
+
+impl ShmCondvar {
+    /// # Safety
+    /// Must be called exactly once, before any process uses the condition variable
+    pub unsafe fn init(&self) -> Result<(), Error> {
+        let mut attr: MaybeUninit<pthread_condattr_t> = MaybeUninit::uninit();
+        check(libc::pthread_condattr_init(attr.as_mut_ptr()), Error::CondvarFailed)?;
+
+        // Timeouts should not depend on the wall clock
+        let res = check(libc::pthread_condattr_setpshared(attr.as_mut_ptr(), PTHREAD_PROCESS_SHARED), Error::CondvarFailed)
+            .and_then(|_| check(libc::pthread_condattr_setclock(attr.as_mut_ptr(), CLOCK_MONOTONIC), Error::CondvarFailed))
+            .and_then(|_| check(libc::pthread_cond_init(self.raw.get(), attr.as_ptr()), Error::CondvarFailed));
+
+        libc::pthread_condattr_destroy(attr.as_mut_ptr());
+        res
+    }
+
+    /// Release the lock until notified.
+    /// Spurious wake ups can happen: always re-check the condition.
+    pub fn wait<'a, T>(&self, guard: ShmMutexGuard<'a, T>) -> Result<ShmMutexGuard<'a, T>, Error> {
+        let mutex = guard.mutex;
+        let inherited = guard.inherited;
+        // The lock is released and re-acquired by pthread_cond_wait
+        mem::forget(guard);
+
+        let code = unsafe { libc::pthread_cond_wait(self.raw.get(), mutex.raw.get()) };
+        let mut guard = mutex.guard_after_wait(code)?;
+        guard.inherited |= inherited;
+        Ok(guard)
+    }
+
+    /// Same as `wait`, but gives up after `timeout`.
+    /// The boolean tells whether the wait timed out.
+    pub fn wait_timeout<'a, T>(&self, guard: ShmMutexGuard<'a, T>, timeout: Duration) -> Result<(ShmMutexGuard<'a, T>, bool), Error> {
+        let deadline = monotonic_deadline(timeout)?;
+        let mutex = guard.mutex;
+        let inherited = guard.inherited;
+        mem::forget(guard);
+
+        let code = unsafe { libc::pthread_cond_timedwait(self.raw.get(), mutex.raw.get(), &deadline) };
+        let (mut guard, timed_out) = match code {
+            ETIMEDOUT => (mutex.guard_after_wait(0)?, true),
+            _         => (mutex.guard_after_wait(code)?, false),
+        };
+        guard.inherited |= inherited;
+        Ok((guard, timed_out))
+    }
+
+    pub fn notify_one(&self) -> Result<(), Error> {
+        let code = unsafe { libc::pthread_cond_signal(self.raw.get()) };
+        check(code, Error::CondvarFailed)
+    }
+
+    pub fn notify_all(&self) -> Result<(), Error> {
+        let code = unsafe { libc::pthread_cond_broadcast(self.raw.get()) };
+        check(code, Error::CondvarFailed)
+    }
+}
+
+fn monotonic_deadline(timeout: Duration) -> Result<libc::timespec, Error> {
+    let mut now: libc::timespec = unsafe { mem::zeroed() };
+    if unsafe { libc::clock_gettime(CLOCK_MONOTONIC, &mut now) } < 0 {
+        return Err(Error::CondvarFailed(io::Error::last_os_error()));
+    }
+
+    let nanos = now.tv_nsec as u64 + timeout.subsec_nanos() as u64;
+    let secs = (now.tv_sec as u64)
+                .saturating_add(timeout.as_secs())
+                .saturating_add(nanos / 1_000_000_000);
+    Ok(libc::timespec {
+        tv_sec:  secs.min(libc::time_t::MAX as u64) as libc::time_t,
+        tv_nsec: (nanos % 1_000_000_000) as _,
+    })
+}
@@ -1,35 +1,468 @@
-use std::{io::{self, Read, Write}, string::FromUtf8Error, slice};
+use std::{error, fmt, io::{self, Read, Write}, string::FromUtf8Error, mem, ptr};
+use std::collections::VecDeque;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
 
-// FIXME this is extremely inefficient, we're reading bytes one by one
-fn read_one<T: Read>(reader: &mut T) -> io::Result<u8> {
-    let mut single_byte: u8 = 0;
-    reader.read_exact(slice::from_mut(&mut single_byte))?;
-    Ok(single_byte)
-}
+/* Typed messages */
+pub mod protocol;
 
 #[derive(Debug)]
 pub enum Error {
     ReadError(io::Error),
     InputNotUtf8(FromUtf8Error),
+    /// The message didn't carry a file descriptor
+    NoFdReceived,
+    /// The kernel dropped file descriptors sent by the peer.
+    /// The connection can't be used anymore: the remaining ones wouldn't match their messages.
+    FdsTruncated,
+    /// The peer sent more file descriptors than its messages can carry
+    TooManyFds(usize),
+    /// The peer sent a string longer than the allowed maximum
+    StringTooLong(usize),
+    /// The peer announced a message longer than the allowed maximum
+    MessageTooLong(usize),
+    /// The content of a message doesn't match its type
+    MalformedMessage,
+    UnknownMessage(u8),
+    /// The peer doesn't speak our protocol at all
+    BadMagic(u32),
+    /// The peer picked a protocol version that we don't speak
+    UnsupportedVersion(u32),
+    /// The server only speaks versions `min_version` to `max_version`
+    VersionRejected { min_version: u32, max_version: u32, ours: u32 },
 }
 
-pub fn read_null_terminated_string<T: Read>(reader: &mut T) -> Result<String, Error> {
-    let mut buffer = Vec::new();
-    loop {
-        let c = read_one(reader)
-                        .map_err(|io_err| Error::ReadError(io_err))?;
-        if c == b'\0' {
-            // End of the string
-            return String::from_utf8(buffer)
-                    .map_err(|conv_err| Error::InputNotUtf8(conv_err));
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::ReadError(_)        => write!(f, "could not read from the socket"),
+            Error::InputNotUtf8(_)     => write!(f, "received a string that is not UTF-8"),
+            Error::NoFdReceived        => write!(f, "expected a file descriptor from the peer"),
+            Error::FdsTruncated        => write!(f, "file descriptors sent by the peer were dropped"),
+            Error::TooManyFds(max)     => write!(f, "the peer sent more than {max} file descriptors at once"),
+            Error::StringTooLong(max)  => write!(f, "received a string longer than {max} bytes"),
+            Error::MessageTooLong(len) => write!(f, "received a message of {len} bytes, which is too long"),
+            Error::MalformedMessage    => write!(f, "received a malformed message"),
+            Error::UnknownMessage(tag) => write!(f, "received an unknown message type ({tag})"),
+            Error::BadMagic(magic)     => write!(f, "the peer is not speaking our protocol (magic {magic:#x})"),
+            Error::UnsupportedVersion(version) => write!(f, "protocol version {version} is not supported"),
+            Error::VersionRejected { min_version, max_version, ours } => {
+                write!(f, "the server only supports protocol versions {min_version} to {max_version}, we speak version {ours}")
+            }
         }
-        else {
-            buffer.push(c);
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::ReadError(e)    => Some(e),
+            Error::InputNotUtf8(e) => Some(e),
+            Error::NoFdReceived
+            | Error::FdsTruncated
+            | Error::TooManyFds(_)
+            | Error::StringTooLong(_)
+            | Error::MessageTooLong(_)
+            | Error::MalformedMessage
+            | Error::UnknownMessage(_)
+            | Error::BadMagic(_)
+            | Error::UnsupportedVersion(_)
+            | Error::VersionRejected { .. } => None,
         }
     }
 }
 
+impl From<io::Error> for Error {
+    fn from(value: io::Error) -> Self {
+        Error::ReadError(value)
+    }
+}
+
 pub fn write_string_null_terminate<W: Write>(writer: &mut W, message: &str) -> io::Result<()> {
     writer.write_all(message.as_bytes())?;
     writer.write_all(b"\0")
 }
+
+/* File descriptor passing */
+
+/// Data byte accompanying a file descriptor.
+/// Linux doesn't transmit ancillary data on its own.
+const FD_MARKER: u8 = b'F';
+
+/// How many descriptors we can receive in a single read.
+/// We only ever send one at a time.
+const MAX_FDS_PER_READ: usize = 4;
+
+/// How many received descriptors may wait for their message.
+/// Each message carries at most one, so this is already generous.
+const MAX_QUEUED_FDS: usize = 16;
+
+/// Large enough and aligned for a `SCM_RIGHTS` control message
+/// with `MAX_FDS_PER_READ` descriptors
+type FdControlBuffer = [u64; 8];
+
+fn fd_control_len(fd_count: usize) -> usize {
+    unsafe { libc::CMSG_SPACE((fd_count * mem::size_of::<RawFd>()) as u32) as usize }
+}
+
+/// Send a file descriptor to the peer (`SCM_RIGHTS`).
+/// The peer gets its own copy: we can close ours right after.
+/// Receive it with `Reader::recv_fd`.
+pub fn send_fd(stream: &UnixStream, fd: BorrowedFd<'_>) -> io::Result<()> {
+    send_with_fd(stream, &[FD_MARKER], fd)
+}
+
+/// Send `data`, with `fd` attached to its first byte
+pub fn send_with_fd(stream: &UnixStream, data: &[u8], fd: BorrowedFd<'_>) -> io::Result<()> {
+    let mut iov = libc::iovec {
+        iov_base: data.as_ptr() as *mut _,
+        iov_len:  data.len(),
+    };
+    let mut control: FdControlBuffer = [0; 8];
+
+    let sent = unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = fd_control_len(1) as _;
+
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<RawFd>() as u32) as _;
+        ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd.as_raw_fd());
+
+        libc::sendmsg(stream.as_raw_fd(), &msg, libc::MSG_NOSIGNAL)
+    };
+
+    match sent {
+        0 if !data.is_empty() => Err(io::ErrorKind::WriteZero.into()),
+        // The descriptor went along with the first part, send the rest normally
+        n if n >= 0 => (&*stream).write_all(&data[n as usize..]),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/* Peer credentials */
+
+/// Identity of the process at the other end of the socket,
+/// as it was when the connection was established
+#[derive(Clone, Copy, Debug)]
+pub struct PeerCredentials {
+    pub pid: libc::pid_t,
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+}
+
+/// Ask the kernel who we are talking to (`SO_PEERCRED`).
+/// Unlike anything the peer sends, this can't be forged.
+pub fn peer_credentials(stream: &UnixStream) -> io::Result<PeerCredentials> {
+    let mut cred: libc::ucred = unsafe { mem::zeroed() };
+    let mut cred_len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+
+    let res = unsafe {
+        libc::getsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERCRED, (&mut cred as *mut libc::ucred).cast(), &mut cred_len)
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(PeerCredentials {
+        pid: cred.pid,
+        uid: cred.uid,
+        gid: cred.gid,
+    })
+}
+
+/* Buffered reader */
+
+const READ_CHUNK_SIZE: usize = 4096;
+/// Enough for any path
+pub const DEFAULT_MAX_STRING_LEN: usize = 4096;
+
+/// Reads the messages sent by the peer in chunks rather than byte by byte.
+/// Bytes read past the end of a message are kept for the next one.
+///
+/// File descriptors sent with `send_fd` are collected along the way:
+/// this must be the only reader of the socket, otherwise they may get lost.
+pub struct Reader<S> {
+    stream: S,
+    buffer: Box<[u8]>,
+    /// Unread bytes are `buffer[start..end]`
+    start:  usize,
+    end:    usize,
+    fds:    VecDeque<QueuedFd>,
+    /// Bytes received from the socket so far
+    received: u64,
+    /// Set once descriptors were lost, every later read fails
+    broken: bool,
+    max_string_len: usize,
+}
+
+impl<S: AsFd> Reader<S> {
+    pub fn new(stream: S) -> Self {
+        Self::with_max_string_len(stream, DEFAULT_MAX_STRING_LEN)
+    }
+
+    /// `max_string_len` doesn't count the null terminator
+    pub fn with_max_string_len(stream: S, max_string_len: usize) -> Self {
+        Self {
+            stream,
+            buffer: vec![0; READ_CHUNK_SIZE.max(max_string_len + 1)].into_boxed_slice(),
+            start:  0,
+            end:    0,
+            fds:    VecDeque::new(),
+            received: 0,
+            broken: false,
+            max_string_len,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    fn buffered(&self) -> &[u8] {
+        &self.buffer[self.start..self.end]
+    }
+
+    fn consume(&mut self, count: usize) {
+        self.start += count;
+    }
+
+    /// Read whatever the peer sent next, at most one chunk.
+    /// Fails on end of file.
+    fn fill(&mut self) -> Result<(), Error> {
+        if self.broken {
+            return Err(Error::FdsTruncated);
+        }
+        // Make room at the end of the buffer
+        if self.start == self.end {
+            self.start = 0;
+            self.end = 0;
+        }
+        else if self.end == self.buffer.len() {
+            self.buffer.copy_within(self.start..self.end, 0);
+            self.end -= self.start;
+            self.start = 0;
+        }
+
+        let free_space = &mut self.buffer[self.end..];
+        let mut arrived = Vec::new();
+        let received = match recv_with_fds(self.stream.as_fd(), free_space, &mut arrived) {
+            Ok(received) => received,
+            Err(Error::FdsTruncated) => {
+                // The bytes just read are gone too: nothing after them can be trusted
+                self.broken = true;
+                self.fds.clear();
+                return Err(Error::FdsTruncated);
+            }
+            Err(e) => return Err(e),
+        };
+        if received == 0 {
+            return Err(Error::ReadError(io::ErrorKind::UnexpectedEof.into()));
+        }
+        self.end += received;
+        self.received += received as u64;
+
+        let arrived_by = self.received;
+        self.fds.extend(arrived.into_iter().map(|fd| QueuedFd { fd, arrived_by }));
+        if self.fds.len() > MAX_QUEUED_FDS {
+            self.broken = true;
+            self.fds.clear();
+            return Err(Error::TooManyFds(MAX_QUEUED_FDS));
+        }
+        Ok(())
+    }
+
+    /// Whether the peer closed the connection, with nothing left to read.
+    /// Blocks until there is something to read or the connection is closed.
+    pub fn at_eof(&mut self) -> Result<bool, Error> {
+        if self.start != self.end {
+            return Ok(false);
+        }
+        match self.fill() {
+            Ok(()) => Ok(false),
+            Err(Error::ReadError(e)) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(true),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn read_null_terminated_string(&mut self) -> Result<String, Error> {
+        // Don't search the same bytes again after each read
+        let mut searched = 0;
+        loop {
+            let pending = self.buffered();
+            if let Some(pos) = pending[searched..].iter().position(|&c| c == b'\0') {
+                let string_len = searched + pos;
+                // It may have arrived in one go, along with the terminator
+                if string_len > self.max_string_len {
+                    return Err(Error::StringTooLong(self.max_string_len));
+                }
+                let bytes = pending[..string_len].to_vec();
+                self.consume(string_len + 1);
+                return String::from_utf8(bytes)
+                        .map_err(Error::InputNotUtf8);
+            }
+
+            searched = pending.len();
+            if searched > self.max_string_len {
+                return Err(Error::StringTooLong(self.max_string_len));
+            }
+            self.fill()?;
+        }
+    }
+
+    /// Take the next file descriptor that arrived with the data read so far
+    fn pop_fd(&mut self) -> Result<OwnedFd, Error> {
+        self.fds
+            .pop_front()
+            .map(|queued| queued.fd)
+            .ok_or(Error::NoFdReceived)
+    }
+
+    /// Close the descriptors that came with bytes already consumed.
+    /// Returns whether there were any: the message that carried them didn't want them.
+    fn drop_stray_fds(&mut self) -> bool {
+        let consumed = self.received - (self.end - self.start) as u64;
+        let count = self.fds.len();
+        self.fds.retain(|queued| queued.arrived_by > consumed);
+        self.fds.len() != count
+    }
+
+    /// Receive a file descriptor sent with `send_fd`.
+    /// The returned fd is close-on-exec.
+    pub fn recv_fd(&mut self) -> Result<OwnedFd, Error> {
+        let mut marker: u8 = 0;
+        self.read_exact(std::slice::from_mut(&mut marker))?;
+        if marker != FD_MARKER {
+            return Err(Error::NoFdReceived);
+        }
+        // The descriptor arrives together with the marker
+        self.pop_fd()
+    }
+}
+
+impl<S: AsFd> Read for Reader<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.start == self.end && !buf.is_empty() {
+            match self.fill() {
+                Ok(()) => {},
+                Err(Error::ReadError(e)) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+                Err(Error::ReadError(e)) => return Err(e),
+                Err(e) => return Err(io::Error::other(e)),
+            }
+        }
+
+        let pending = self.buffered();
+        let count = pending.len().min(buf.len());
+        buf[..count].copy_from_slice(&pending[..count]);
+        self.consume(count);
+        Ok(count)
+    }
+}
+
+/// A descriptor waiting for the message it came with
+struct QueuedFd {
+    fd:         OwnedFd,
+    /// The descriptor came with one of the bytes before this position in the stream.
+    /// The kernel doesn't tell which one exactly.
+    arrived_by: u64,
+}
+
+/// Read into `buf`, collecting any file descriptor that comes along
+fn recv_with_fds(stream: BorrowedFd<'_>, buf: &mut [u8], fds: &mut Vec<OwnedFd>) -> Result<usize, Error> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr().cast(),
+        iov_len:  buf.len(),
+    };
+    let mut control: FdControlBuffer = [0; 8];
+
+    unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = fd_control_len(MAX_FDS_PER_READ) as _;
+
+        let received = loop {
+            let received = libc::recvmsg(stream.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC);
+            if received >= 0 {
+                break received as usize;
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(Error::ReadError(err));
+            }
+        };
+
+        let queued = fds.len();
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data_len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                for i in 0..data_len / mem::size_of::<RawFd>() {
+                    let fd = ptr::read_unaligned(data.add(i));
+                    fds.push(OwnedFd::from_raw_fd(fd));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+
+        if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+            // Some descriptors were dropped by the kernel: close the ones that made it
+            fds.truncate(queued);
+            return Err(Error::FdsTruncated);
+        }
+        Ok(received)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_leftover_bytes_for_the_next_message() {
+        let (mut ours, theirs) = UnixStream::pair().unwrap();
+        ours.write_all(b"first\0second\0rest").unwrap();
+        drop(ours);
+
+        let mut reader = Reader::new(&theirs);
+        assert_eq!(reader.read_null_terminated_string().unwrap(), "first");
+        assert_eq!(reader.read_null_terminated_string().unwrap(), "second");
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"rest");
+        assert!(reader.at_eof().unwrap());
+    }
+
+    #[test]
+    fn moves_a_split_message_to_the_start_of_the_buffer() {
+        let (mut ours, theirs) = UnixStream::pair().unwrap();
+        let mut reader = Reader::new(&theirs);
+        // Fill the buffer but for a few bytes, then cross its end
+        let filler_count = (reader.buffer.len() - 4) / 8;
+        let mut data = b"filler.\0".repeat(filler_count);
+        data.extend_from_slice(b"crosses the end\0");
+        ours.write_all(&data).unwrap();
+
+        for _ in 0..filler_count {
+            assert_eq!(reader.read_null_terminated_string().unwrap(), "filler.");
+        }
+        assert_eq!(reader.read_null_terminated_string().unwrap(), "crosses the end");
+        assert!(reader.end < reader.buffer.len() / 2, "the buffer was not compacted");
+    }
+
+    #[test]
+    fn limits_the_string_length() {
+        let (mut ours, theirs) = UnixStream::pair().unwrap();
+        ours.write_all(b"12345678\x00123456789\0").unwrap();
+
+        let mut reader = Reader::with_max_string_len(&theirs, 8);
+        assert_eq!(reader.read_null_terminated_string().unwrap(), "12345678");
+        assert!(matches!(reader.read_null_terminated_string(), Err(Error::StringTooLong(8))));
+    }
+}
@@ -0,0 +1,654 @@
+//! Messages exchanged between the client and the server
+//!
+//! Every message is sent as a frame: a `u32` payload length, then the payload.
+//! The payload starts with a one byte tag identifying the message type,
+//! followed by the fields of the message. Integers are little-endian
+//! and fixed width, strings are a `u32` length followed by UTF-8 bytes.
+//! File descriptors travel alongside the frame (`SCM_RIGHTS`).
+
+use std::{fmt, io::{self, Read, Write}};
+use std::os::fd::{AsFd, BorrowedFd, OwnedFd};
+use std::os::unix::net::UnixStream;
+use std::collections::VecDeque;
+
+use super::{send_with_fd, Error, QueuedFd, Reader};
+
+/// Frames larger than this are rejected before allocating anything
+pub const MAX_MESSAGE_LEN: usize = 64 * 1024;
+
+pub trait Message: Sized {
+    fn encode(&self, encoder: &mut Encoder);
+    fn decode(decoder: &mut Decoder<'_>) -> Result<Self, Error>;
+
+    /// Descriptor to send along with the message, if any
+    fn fd(&self) -> Option<BorrowedFd<'_>> {
+        None
+    }
+}
+
+/* Version negotiation */
+
+/// Version of the messages below.
+/// Bump it whenever their encoding changes.
+pub const PROTOCOL_VERSION: u32 = 3;
+/// Oldest version that we can still speak.
+/// Version 2 changed `Response::Error`, version 3 added request ids.
+/// While it equals `PROTOCOL_VERSION`, there is no downgrade:
+/// a peer speaking any other version gets its connection rejected.
+pub const MIN_PROTOCOL_VERSION: u32 = 3;
+
+/// Starts every hello, so that we can tell we're talking to one of us
+const HELLO_MAGIC: u32 = u32::from_le_bytes(*b"SUMR");
+
+/// Optional features, negotiated during the hello.
+/// Bit 0 is unused: shared memory is always sealed in the versions we speak.
+pub mod capabilities {
+    /// Large files may come through a `shm::stream` ring, see `Response::Stream`
+    pub const STREAM: u64 = 1 << 1;
+    /// Files may be handed out as is, see `Response::FileHandle`
+    pub const MAPPED_FILE: u64 = 1 << 2;
+
+    /// Everything this build supports
+    pub const ALL: u64 = STREAM | MAPPED_FILE;
+}
+
+/// First message sent by the client.
+/// Its encoding must never change, whatever the protocol version.
+#[derive(Debug)]
+pub struct Hello {
+    pub version:      u32,
+    pub capabilities: u64,
+}
+
+/// Server's answer to `Hello`. Same as `Hello`, its encoding must never change.
+#[derive(Debug)]
+pub enum HelloReply {
+    /// Both sides must use this version and these capabilities from now on
+    Accept { version: u32, capabilities: u64 },
+    /// The server doesn't speak the client's version
+    Reject { min_version: u32, max_version: u32 },
+}
+
+const HELLO_TAG:  u8 = 0;
+const ACCEPT_TAG: u8 = 0;
+const REJECT_TAG: u8 = 1;
+
+impl Message for Hello {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.put_u8(HELLO_TAG);
+        encoder.put_u32(HELLO_MAGIC);
+        encoder.put_u32(self.version);
+        encoder.put_u64(self.capabilities);
+    }
+
+    fn decode(decoder: &mut Decoder<'_>) -> Result<Self, Error> {
+        let tag = decoder.get_u8()?;
+        if tag != HELLO_TAG {
+            return Err(Error::UnknownMessage(tag));
+        }
+        let magic = decoder.get_u32()?;
+        if magic != HELLO_MAGIC {
+            return Err(Error::BadMagic(magic));
+        }
+        Ok(Hello {
+            version:      decoder.get_u32()?,
+            capabilities: decoder.get_u64()?,
+        })
+    }
+}
+
+impl Message for HelloReply {
+    fn encode(&self, encoder: &mut Encoder) {
+        match self {
+            HelloReply::Accept { version, capabilities } => {
+                encoder.put_u8(ACCEPT_TAG);
+                encoder.put_u32(HELLO_MAGIC);
+                encoder.put_u32(*version);
+                encoder.put_u64(*capabilities);
+            }
+            HelloReply::Reject { min_version, max_version } => {
+                encoder.put_u8(REJECT_TAG);
+                encoder.put_u32(HELLO_MAGIC);
+                encoder.put_u32(*min_version);
+                encoder.put_u32(*max_version);
+            }
+        }
+    }
+
+    fn decode(decoder: &mut Decoder<'_>) -> Result<Self, Error> {
+        let tag = decoder.get_u8()?;
+        let magic = decoder.get_u32()?;
+        if magic != HELLO_MAGIC {
+            return Err(Error::BadMagic(magic));
+        }
+        match tag {
+            ACCEPT_TAG => Ok(HelloReply::Accept {
+                version:      decoder.get_u32()?,
+                capabilities: decoder.get_u64()?,
+            }),
+            REJECT_TAG => Ok(HelloReply::Reject {
+                min_version: decoder.get_u32()?,
+                max_version: decoder.get_u32()?,
+            }),
+            tag => Err(Error::UnknownMessage(tag)),
+        }
+    }
+}
+
+/// Outcome of the hello exchange
+#[derive(Clone, Copy, Debug)]
+pub struct Negotiated {
+    pub version:      u32,
+    pub capabilities: u64,
+}
+
+impl Negotiated {
+    pub fn has(&self, capability: u64) -> bool {
+        self.capabilities & capability == capability
+    }
+}
+
+/// Introduce ourselves to the server. Must be the first exchange on the connection.
+pub fn client_hello<S: AsFd>(stream: &UnixStream, reader: &mut Reader<S>, capabilities: u64) -> Result<Negotiated, Error> {
+    let hello = Hello {
+        version: PROTOCOL_VERSION,
+        capabilities,
+    };
+    send(stream, &hello)?;
+
+    match reader.receive()? {
+        HelloReply::Accept { version, capabilities: accepted } => {
+            // The server must pick something we speak and offered
+            if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+                return Err(Error::UnsupportedVersion(version));
+            }
+            Ok(Negotiated { version, capabilities: accepted & capabilities })
+        }
+        HelloReply::Reject { min_version, max_version } => {
+            Err(Error::VersionRejected { min_version, max_version, ours: PROTOCOL_VERSION })
+        }
+    }
+}
+
+/// Wait for the client's hello and agree on a version,
+/// downgrading to the client's version if it is older and still in our range.
+/// Clients that are too old are told which versions we speak, then rejected.
+/// Clients that are newer reject our choice if they can't downgrade to it.
+pub fn server_hello<S: AsFd>(stream: &UnixStream, reader: &mut Reader<S>, capabilities: u64) -> Result<Negotiated, Error> {
+    let hello: Hello = reader.receive()?;
+
+    if hello.version < MIN_PROTOCOL_VERSION {
+        let reply = HelloReply::Reject {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+        };
+        send(stream, &reply)?;
+        return Err(Error::UnsupportedVersion(hello.version));
+    }
+
+    let negotiated = Negotiated {
+        version:      hello.version.min(PROTOCOL_VERSION),
+        capabilities: hello.capabilities & capabilities,
+    };
+    let reply = HelloReply::Accept {
+        version:      negotiated.version,
+        capabilities: negotiated.capabilities,
+    };
+    send(stream, &reply)?;
+    Ok(negotiated)
+}
+
+/* Messages */
+
+/// Identifies a request within a connection, chosen by the client.
+/// The response to the request carries the same id.
+pub type RequestId = u64;
+
+/// Sent by the client, any number of times per connection.
+/// The client doesn't have to wait for a response before sending the next request,
+/// and must not assume that responses come in the same order as the requests.
+#[derive(Debug)]
+pub enum Request {
+    OpenFile { id: RequestId, path: String },
+    /// The client is done with the connection
+    Close,
+}
+
+/// Sent by the server, once per `OpenFile`
+#[derive(Debug)]
+pub enum Response {
+    /// Sealed shared memory holding the file content, see `shm::SealedMemory`
+    ShmHandle { id: RequestId, size: u64, fd: OwnedFd },
+    /// The request failed, `message` gives the details for humans
+    Error { id: RequestId, kind: ErrorKind, message: String },
+    /// Shared memory prepared for a `shm::stream` ring, through which the server writes
+    /// the `size` bytes of the file. The client connects as the reader.
+    /// Only sent if `capabilities::STREAM` was negotiated.
+    Stream { id: RequestId, size: u64, fd: OwnedFd },
+    /// The file itself, opened read-only, for the client to map without any copy.
+    /// Unlike shared memory, it can change while mapped, see `shm::MappedFile`.
+    /// Only sent if `capabilities::MAPPED_FILE` was negotiated.
+    FileHandle { id: RequestId, size: u64, fd: OwnedFd },
+}
+
+impl Response {
+    pub fn id(&self) -> RequestId {
+        match self {
+            Response::ShmHandle { id, .. }  => *id,
+            Response::Error { id, .. }      => *id,
+            Response::Stream { id, .. }     => *id,
+            Response::FileHandle { id, .. } => *id,
+        }
+    }
+}
+
+/// Why a request failed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    NotFound,
+    PermissionDenied,
+    /// Larger than what the server accepts to send
+    TooLarge,
+    /// Anything else, the client can't do much about it
+    Internal,
+}
+
+impl ErrorKind {
+    /// Classify a failure of the server while reading a file
+    pub fn from_io(error: &io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::NotFound
+            | io::ErrorKind::NotADirectory  => ErrorKind::NotFound,
+            io::ErrorKind::PermissionDenied => ErrorKind::PermissionDenied,
+            io::ErrorKind::FileTooLarge     => ErrorKind::TooLarge,
+            _                               => ErrorKind::Internal,
+        }
+    }
+
+    fn to_wire(self) -> u8 {
+        match self {
+            ErrorKind::NotFound         => 1,
+            ErrorKind::PermissionDenied => 2,
+            ErrorKind::TooLarge         => 3,
+            ErrorKind::Internal         => 4,
+        }
+    }
+
+    fn from_wire(value: u8) -> Self {
+        match value {
+            1 => ErrorKind::NotFound,
+            2 => ErrorKind::PermissionDenied,
+            3 => ErrorKind::TooLarge,
+            // Kinds added by newer servers are still errors
+            _ => ErrorKind::Internal,
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::NotFound         => write!(f, "no such file"),
+            ErrorKind::PermissionDenied => write!(f, "permission denied"),
+            ErrorKind::TooLarge         => write!(f, "file too large"),
+            ErrorKind::Internal         => write!(f, "internal server error"),
+        }
+    }
+}
+
+const OPEN_FILE_TAG: u8 = 1;
+const CLOSE_TAG:     u8 = 2;
+
+const SHM_HANDLE_TAG:  u8 = 1;
+const ERROR_TAG:       u8 = 2;
+const STREAM_TAG:      u8 = 3;
+const FILE_HANDLE_TAG: u8 = 4;
+
+impl Message for Request {
+    fn encode(&self, encoder: &mut Encoder) {
+        match self {
+            Request::OpenFile { id, path } => {
+                encoder.put_u8(OPEN_FILE_TAG);
+                encoder.put_u64(*id);
+                encoder.put_str(path);
+            }
+            Request::Close => {
+                encoder.put_u8(CLOSE_TAG);
+            }
+        }
+    }
+
+    fn decode(decoder: &mut Decoder<'_>) -> Result<Self, Error> {
+        match decoder.get_u8()? {
+            OPEN_FILE_TAG => Ok(Request::OpenFile {
+                id:   decoder.get_u64()?,
+                path: decoder.get_string()?,
+            }),
+            CLOSE_TAG     => Ok(Request::Close),
+            tag           => Err(Error::UnknownMessage(tag)),
+        }
+    }
+}
+
+impl Message for Response {
+    fn encode(&self, encoder: &mut Encoder) {
+        match self {
+            Response::ShmHandle { id, size, fd: _ } => {
+                encoder.put_u8(SHM_HANDLE_TAG);
+                encoder.put_u64(*id);
+                encoder.put_u64(*size);
+            }
+            Response::Stream { id, size, fd: _ } => {
+                encoder.put_u8(STREAM_TAG);
+                encoder.put_u64(*id);
+                encoder.put_u64(*size);
+            }
+            Response::FileHandle { id, size, fd: _ } => {
+                encoder.put_u8(FILE_HANDLE_TAG);
+                encoder.put_u64(*id);
+                encoder.put_u64(*size);
+            }
+            Response::Error { id, kind, message } => {
+                encoder.put_u8(ERROR_TAG);
+                encoder.put_u64(*id);
+                encoder.put_u8(kind.to_wire());
+                encoder.put_str(message);
+            }
+        }
+    }
+
+    fn decode(decoder: &mut Decoder<'_>) -> Result<Self, Error> {
+        match decoder.get_u8()? {
+            SHM_HANDLE_TAG  => Ok(Response::ShmHandle {
+                id:   decoder.get_u64()?,
+                size: decoder.get_u64()?,
+                fd:   decoder.get_fd()?,
+            }),
+            STREAM_TAG      => Ok(Response::Stream {
+                id:   decoder.get_u64()?,
+                size: decoder.get_u64()?,
+                fd:   decoder.get_fd()?,
+            }),
+            FILE_HANDLE_TAG => Ok(Response::FileHandle {
+                id:   decoder.get_u64()?,
+                size: decoder.get_u64()?,
+                fd:   decoder.get_fd()?,
+            }),
+            ERROR_TAG       => Ok(Response::Error {
+                id:      decoder.get_u64()?,
+                kind:    ErrorKind::from_wire(decoder.get_u8()?),
+                message: decoder.get_string()?,
+            }),
+            tag             => Err(Error::UnknownMessage(tag)),
+        }
+    }
+
+    fn fd(&self) -> Option<BorrowedFd<'_>> {
+        match self {
+            Response::ShmHandle { fd, .. }  => Some(fd.as_fd()),
+            Response::Stream { fd, .. }     => Some(fd.as_fd()),
+            Response::FileHandle { fd, .. } => Some(fd.as_fd()),
+            _ => None,
+        }
+    }
+}
+
+/* Encoding */
+
+pub struct Encoder {
+    buffer: Vec<u8>,
+}
+
+impl Encoder {
+    fn new() -> Self {
+        // Leave room for the length, filled in by `finish`
+        Self {
+            buffer: vec![0; 4],
+        }
+    }
+
+    pub fn put_u8(&mut self, value: u8) {
+        self.buffer.push(value);
+    }
+
+    pub fn put_u32(&mut self, value: u32) {
+        self.buffer.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn put_u64(&mut self, value: u64) {
+        self.buffer.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn put_str(&mut self, value: &str) {
+        self.put_u32(value.len() as u32);
+        self.buffer.extend_from_slice(value.as_bytes());
+    }
+
+    fn finish(mut self) -> io::Result<Vec<u8>> {
+        let payload_len = self.buffer.len() - 4;
+        if payload_len > MAX_MESSAGE_LEN {
+            // The peer would reject it anyway
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, Error::MessageTooLong(payload_len)));
+        }
+        self.buffer[..4].copy_from_slice(&(payload_len as u32).to_le_bytes());
+        Ok(self.buffer)
+    }
+}
+
+/// Encode the message and send it in one go
+pub fn send<M: Message>(stream: &UnixStream, message: &M) -> io::Result<()> {
+    let mut encoder = Encoder::new();
+    message.encode(&mut encoder);
+    let frame = encoder.finish()?;
+
+    match message.fd() {
+        Some(fd) => send_with_fd(stream, &frame, fd),
+        None     => (&*stream).write_all(&frame),
+    }
+}
+
+/* Decoding */
+
+pub struct Decoder<'a> {
+    payload: &'a [u8],
+    fds:     &'a mut VecDeque<QueuedFd>,
+}
+
+impl Decoder<'_> {
+    fn take(&mut self, count: usize) -> Result<&[u8], Error> {
+        if self.payload.len() < count {
+            return Err(Error::MalformedMessage);
+        }
+        let (taken, rest) = self.payload.split_at(count);
+        self.payload = rest;
+        Ok(taken)
+    }
+
+    pub fn get_u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn get_u32(&mut self) -> Result<u32, Error> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub fn get_u64(&mut self) -> Result<u64, Error> {
+        let bytes = self.take(8)?;
+        Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub fn get_string(&mut self) -> Result<String, Error> {
+        let len = self.get_u32()? as usize;
+        let bytes = self.take(len)?.to_vec();
+        String::from_utf8(bytes)
+            .map_err(Error::InputNotUtf8)
+    }
+
+    /// The next descriptor that came with the messages
+    pub fn get_fd(&mut self) -> Result<OwnedFd, Error> {
+        self.fds
+            .pop_front()
+            .map(|queued| queued.fd)
+            .ok_or(Error::NoFdReceived)
+    }
+}
+
+impl<S: AsFd> Reader<S> {
+    /// Wait for the next message.
+    /// Fails with `ReadError(UnexpectedEof)` if the peer closed the connection,
+    /// and with `MalformedMessage` if it attached descriptors that the message doesn't carry.
+    pub fn receive<M: Message>(&mut self) -> Result<M, Error> {
+        let mut len_bytes = [0; 4];
+        self.read_exact(&mut len_bytes)?;
+        let payload_len = u32::from_le_bytes(len_bytes) as usize;
+        if payload_len > MAX_MESSAGE_LEN {
+            return Err(Error::MessageTooLong(payload_len));
+        }
+
+        let mut payload = vec![0; payload_len];
+        self.read_exact(&mut payload)?;
+
+        let mut decoder = Decoder {
+            payload: &payload,
+            fds:     &mut self.fds,
+        };
+        let message = M::decode(&mut decoder);
+        let trailing_bytes = !decoder.payload.is_empty();
+        // Otherwise they would pile up until we run out of descriptors
+        if self.drop_stray_fds() {
+            return Err(Error::MalformedMessage);
+        }
+        let message = message?;
+        if trailing_bytes {
+            return Err(Error::MalformedMessage);
+        }
+        Ok(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, os::unix::fs::MetadataExt};
+
+    use super::*;
+
+    fn round_trip<M: Message>(message: &M) -> M {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        send(&ours, message).unwrap();
+        Reader::new(&theirs).receive().unwrap()
+    }
+
+    /// Send `payload` as a frame, bypassing the encoder
+    fn receive_raw<M: Message>(payload: &[u8]) -> Result<M, Error> {
+        let (mut ours, theirs) = UnixStream::pair().unwrap();
+        ours.write_all(&(payload.len() as u32).to_le_bytes()).unwrap();
+        ours.write_all(payload).unwrap();
+        Reader::new(&theirs).receive()
+    }
+
+    fn same_file(fd: OwnedFd, expected: &File) -> bool {
+        let received = File::from(fd).metadata().unwrap();
+        let expected = expected.metadata().unwrap();
+        (received.dev(), received.ino()) == (expected.dev(), expected.ino())
+    }
+
+    #[test]
+    fn hellos_round_trip() {
+        let hello = round_trip(&Hello { version: 7, capabilities: capabilities::ALL });
+        assert_eq!((hello.version, hello.capabilities), (7, capabilities::ALL));
+
+        let accept = round_trip(&HelloReply::Accept { version: 3, capabilities: capabilities::STREAM });
+        assert!(matches!(accept, HelloReply::Accept { version: 3, capabilities: capabilities::STREAM }));
+
+        let reject = round_trip(&HelloReply::Reject { min_version: 2, max_version: 5 });
+        assert!(matches!(reject, HelloReply::Reject { min_version: 2, max_version: 5 }));
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        let path = "dir/fichier é.txt";
+        match round_trip(&Request::OpenFile { id: u64::MAX, path: path.to_owned() }) {
+            Request::OpenFile { id, path: received } => assert_eq!((id, received.as_str()), (u64::MAX, path)),
+            other => panic!("unexpected {other:?}"),
+        }
+        assert!(matches!(round_trip(&Request::Close), Request::Close));
+    }
+
+    #[test]
+    fn responses_with_fds_round_trip() {
+        let file = File::open("/dev/null").unwrap();
+        let fd = || file.as_fd().try_clone_to_owned().unwrap();
+
+        match round_trip(&Response::ShmHandle { id: 1, size: 10, fd: fd() }) {
+            Response::ShmHandle { id: 1, size: 10, fd } => assert!(same_file(fd, &file)),
+            other => panic!("unexpected {other:?}"),
+        }
+        match round_trip(&Response::Stream { id: 2, size: 20, fd: fd() }) {
+            Response::Stream { id: 2, size: 20, fd } => assert!(same_file(fd, &file)),
+            other => panic!("unexpected {other:?}"),
+        }
+        match round_trip(&Response::FileHandle { id: 3, size: 30, fd: fd() }) {
+            Response::FileHandle { id: 3, size: 30, fd } => assert!(same_file(fd, &file)),
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[test]
+    fn error_responses_round_trip() {
+        let kinds = [ErrorKind::NotFound, ErrorKind::PermissionDenied, ErrorKind::TooLarge, ErrorKind::Internal];
+        for (id, kind) in (0..).zip(kinds) {
+            let error = Response::Error { id, kind, message: format!("details {id}") };
+            match round_trip(&error) {
+                Response::Error { id: received_id, kind: received_kind, message } => {
+                    assert_eq!((received_id, received_kind), (id, kind));
+                    assert_eq!(message, format!("details {id}"));
+                }
+                other => panic!("unexpected {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_frames_over_the_limit() {
+        let (mut ours, theirs) = UnixStream::pair().unwrap();
+        ours.write_all(&(MAX_MESSAGE_LEN as u32 + 1).to_le_bytes()).unwrap();
+        let res: Result<Request, _> = Reader::new(&theirs).receive();
+        assert!(matches!(res, Err(Error::MessageTooLong(len)) if len == MAX_MESSAGE_LEN + 1));
+    }
+
+    #[test]
+    fn rejects_truncated_payloads() {
+        // The id is cut short
+        let res: Result<Request, _> = receive_raw(&[OPEN_FILE_TAG, 1, 0, 0, 0]);
+        assert!(matches!(res, Err(Error::MalformedMessage)));
+        // The string is shorter than announced
+        let mut payload = vec![OPEN_FILE_TAG];
+        payload.extend_from_slice(&1u64.to_le_bytes());
+        payload.extend_from_slice(&10u32.to_le_bytes());
+        payload.extend_from_slice(b"short");
+        let res: Result<Request, _> = receive_raw(&payload);
+        assert!(matches!(res, Err(Error::MalformedMessage)));
+    }
+
+    #[test]
+    fn rejects_trailing_bytes() {
+        let res: Result<Request, _> = receive_raw(&[CLOSE_TAG, 0]);
+        assert!(matches!(res, Err(Error::MalformedMessage)));
+    }
+
+    #[test]
+    fn rejects_unknown_tags() {
+        let res: Result<Request, _> = receive_raw(&[42]);
+        assert!(matches!(res, Err(Error::UnknownMessage(42))));
+    }
+
+    #[test]
+    fn rejects_fds_the_message_does_not_carry() {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let file = File::open("/dev/null").unwrap();
+        send_with_fd(&ours, &[1, 0, 0, 0, CLOSE_TAG], file.as_fd()).unwrap();
+        let res: Result<Request, _> = Reader::new(&theirs).receive();
+        assert!(matches!(res, Err(Error::MalformedMessage)));
+    }
+}
@@ -0,0 +1,136 @@
+//! Recently served files, kept in sealed shared memory
+//!
+//! Sealed memory can't change, so the same region is handed to every client
+//! asking for the file, as long as the file itself doesn't change.
+//! Entries are evicted, least recently used first, to stay within a memory budget.
+//!
+//! Clients don't tell us when they unmap a region: each connection holds on to
+//! the regions it handed out until the client disconnects, and entries held that
+//! way are never evicted. Regions are only freed by the kernel once every client
+//! unmapped them, so a client that keeps a mapping after disconnecting is the
+//! one case the budget doesn't account for.
+
+use std::{collections::HashMap, fs::Metadata, os::unix::fs::MetadataExt, sync::{Arc, Mutex, PoisonError}};
+
+use common::shm::SealedMemory;
+
+/// Identifies a version of a file.
+/// Rather than the requested path, which depends on the client's root,
+/// this uses the identity of the file that the path led to.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    dev:        u64,
+    ino:        u64,
+    mtime:      i64,
+    mtime_nsec: i64,
+    size:       u64,
+}
+
+impl CacheKey {
+    pub fn of(metadata: &Metadata) -> Self {
+        Self {
+            dev:        metadata.dev(),
+            ino:        metadata.ino(),
+            mtime:      metadata.mtime(),
+            mtime_nsec: metadata.mtime_nsec(),
+            size:       metadata.size(),
+        }
+    }
+}
+
+/// Regions kept for later requests, within a budget.
+/// Regions that don't fit are handed out without being cached.
+pub struct FileCache {
+    /// Total size of the cached regions, in bytes
+    budget: usize,
+    state:  Mutex<CacheState>,
+}
+
+struct CacheState {
+    entries: HashMap<CacheKey, Entry>,
+    used:    usize,
+    /// Incremented on each access, orders the entries by last use
+    clock:   u64,
+}
+
+struct Entry {
+    /// Also held by the connections that handed the region out
+    memory:    Arc<SealedMemory>,
+    last_used: u64,
+}
+
+impl FileCache {
+    /// A `budget` of 0 disables the cache
+    pub fn new(budget: usize) -> Self {
+        Self {
+            budget,
+            state: Mutex::new(CacheState {
+                entries: HashMap::new(),
+                used:    0,
+                clock:   0,
+            }),
+        }
+    }
+
+    pub fn get(&self, key: &CacheKey) -> Option<Arc<SealedMemory>> {
+        let mut state = self.lock();
+        state.clock += 1;
+        let clock = state.clock;
+        let entry = state.entries.get_mut(key)?;
+        entry.last_used = clock;
+        Some(Arc::clone(&entry.memory))
+    }
+
+    /// Keep `memory` for later requests, evicting older entries if needed.
+    /// Does nothing if it doesn't fit, e.g. because all the entries are in use.
+    pub fn insert(&self, key: CacheKey, memory: Arc<SealedMemory>) {
+        let size = memory.len();
+        // Empty files fit in any budget, even a disabled cache
+        if self.budget == 0 || size > self.budget {
+            return;
+        }
+
+        let mut state = self.lock();
+        // Another connection loaded the same file in the meantime
+        if state.entries.contains_key(&key) {
+            return;
+        }
+        while state.used + size > self.budget {
+            if !state.evict_one() {
+                return;
+            }
+        }
+
+        state.clock += 1;
+        let entry = Entry {
+            memory,
+            last_used: state.clock,
+        };
+        state.entries.insert(key, entry);
+        state.used += size;
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, CacheState> {
+        // The state is consistent between statements, even if a holder panicked
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl CacheState {
+    /// Drop the least recently used entry that no connected client received.
+    /// Returns false if there is none.
+    fn evict_one(&mut self) -> bool {
+        let victim = self.entries
+                        .iter()
+                        .filter(|(_, entry)| Arc::strong_count(&entry.memory) == 1)
+                        .min_by_key(|(_, entry)| entry.last_used)
+                        .map(|(key, _)| key.clone());
+        match victim.and_then(|key| self.entries.remove(&key)) {
+            Some(entry) => {
+                self.used -= entry.memory.len();
+                true
+            }
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,46 @@
+//! Bounds the number of clients served at the same time
+
+use std::sync::{Condvar, Mutex, PoisonError};
+
+pub struct ConnectionLimit {
+    max:    usize,
+    active: Mutex<usize>,
+    /// Notified whenever a slot is released
+    freed:  Condvar,
+}
+
+/// A connection being served. Releases its slot when dropped,
+/// including when the thread serving it panics.
+pub struct Slot<'a> {
+    limit: &'a ConnectionLimit,
+}
+
+impl ConnectionLimit {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            active: Mutex::new(0),
+            freed:  Condvar::new(),
+        }
+    }
+
+    /// Block until fewer than `max` connections are being served.
+    /// Meanwhile new clients wait in the listen backlog.
+    pub fn acquire(&self) -> Slot<'_> {
+        // The counter stays consistent even if a holder panicked
+        let active = self.active.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut active = self.freed
+                            .wait_while(active, |active| *active >= self.max)
+                            .unwrap_or_else(PoisonError::into_inner);
+        *active += 1;
+        Slot { limit: self }
+    }
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        let mut active = self.limit.active.lock().unwrap_or_else(PoisonError::into_inner);
+        *active -= 1;
+        self.limit.freed.notify_one();
+    }
+}
@@ -1,8 +1,76 @@
-use std::{io::Write, os::unix::net::UnixListener, path::PathBuf, time::Duration, fs};
+use std::{env, fs::File, io::{self, Read}, os::{fd::AsFd, unix::net::{UnixListener, UnixStream}}, path::{Path, PathBuf}, sync::Arc, thread, time::Duration};
 
-use common::{shm::SharedMemory, uds};
+use common::{error::Report, shm::{stream::{self, BuildWriter, StreamWriter}, SealedMemory, SharedMemory}, uds::{self, protocol::{self, capabilities, ErrorKind, Negotiated, Request, Response}}, Error};
+
+mod cache;
+mod limit;
+mod policy;
+mod sandbox;
+use cache::{CacheKey, FileCache};
+use limit::ConnectionLimit;
+use policy::Policy;
+use sandbox::Root;
+
+/// Shows up in /proc/<pid>/fd for the shared memory created by the server
+const SHM_DEBUG_NAME: &str = "sumer\0";
+
+/// Larger files are refused, unless they can be streamed
+const MAX_FILE_SIZE: u64 = 1 << 30;
+
+/// Shows up in /proc/<pid>/fd for the stream rings
+const RING_DEBUG_NAME: &str = "sumer-ring\0";
+/// Size of the shared memory of each stream, header included
+const RING_SIZE: usize = 1 << 20;
+/// Larger files are streamed when the client supports it
+const STREAM_THRESHOLD: u64 = RING_SIZE as u64;
+/// Read from the file and written to the ring at once
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Bytes of files kept in the cache, unless `SUMER_CACHE_BUDGET` says otherwise.
+/// Cached regions are only evicted once no connected client received them.
+const DEFAULT_CACHE_BUDGET: usize = 64 << 20;
+
+/// Clients served at the same time, unless `SUMER_MAX_CONNECTIONS` says otherwise
+const DEFAULT_MAX_CONNECTIONS: usize = 64;
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+/// Silent clients are disconnected after this long, so they don't hold their slot forever
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Shared by all the connections
+struct Server {
+    policy:      Policy,
+    served_root: Root,
+    /// Capabilities offered to the clients
+    offered:     u64,
+    cache:       FileCache,
+}
+
+/// Print a line prefixed by the current connection
+macro_rules! log {
+    ($($arg:tt)*) => {
+        println!("[{}] {}", thread::current().name().unwrap_or("?"), format_args!($($arg)*))
+    };
+}
 
 fn main() {
+    let policy = match Policy::from_env() {
+        Ok(policy) => policy,
+        Err(e) => {
+            println!("Could not load the access policy: {e}");
+            return;
+        }
+    };
+
+    // Only the files below this directory are served
+    let root_dir = env::var_os("SUMER_ROOT").map_or_else(|| PathBuf::from("."), PathBuf::from);
+    let served_root = match Root::open(&root_dir) {
+        Ok(root) => root,
+        Err(e) => {
+            println!("Could not open the served directory {}: {e}", root_dir.display());
+            return;
+        }
+    };
+
     let my_pos = std::env::args().next().unwrap();
     let mut dir = PathBuf::from(my_pos);
     dir.pop();
@@ -10,39 +78,272 @@ fn main() {
     // Ignore error if the file doesn't exist
     let _ = std::fs::remove_file(&dir);
 
-    let listener = UnixListener::bind(&dir).unwrap();
+    let listener = match UnixListener::bind(&dir) {
+        Ok(listener) => listener,
+        Err(e) => {
+            println!("Could not listen on {}: {e}", dir.display());
+            return;
+        }
+    };
     println!("Listening to connections on {}", dir.display());
 
-    match listener.incoming().next() {
-        Some(Ok(mut stream)) => {
-            println!("Connection successful");
-            stream.set_write_timeout(Some(Duration::from_secs(1))).unwrap();
+    let max_connections = match env::var("SUMER_MAX_CONNECTIONS") {
+        Ok(value) => match value.parse() {
+            Ok(max) if max > 0 => max,
+            _ => {
+                println!("Invalid value for SUMER_MAX_CONNECTIONS: {value:?}");
+                return;
+            }
+        },
+        Err(_) => DEFAULT_MAX_CONNECTIONS,
+    };
+    let limit = ConnectionLimit::new(max_connections);
+
+    // Handing out the files themselves saves a copy,
+    // but clients then see any later change to them
+    let offered = match env::var("SUMER_ZERO_COPY").as_deref() {
+        Ok("1") => capabilities::ALL,
+        _       => capabilities::ALL & !capabilities::MAPPED_FILE,
+    };
 
-            let file_to_read = uds::read_null_terminated_string(&mut stream).unwrap();
-            println!("Reading {file_to_read} ...");
+    let cache_budget = match env::var("SUMER_CACHE_BUDGET") {
+        Ok(value) => match value.parse() {
+            Ok(budget) => budget,
+            Err(_) => {
+                println!("Invalid value for SUMER_CACHE_BUDGET: {value:?}");
+                return;
+            }
+        },
+        Err(_) => DEFAULT_CACHE_BUDGET,
+    };
 
-            // Read the data file
-            let data_to_send = fs::read_to_string(file_to_read).unwrap();
-            let data_size = data_to_send.len(); // Returns the number of bytes
+    let server = Server {
+        policy,
+        served_root,
+        offered,
+        cache: FileCache::new(cache_budget),
+    };
 
-            // Open the shared memory and write a basic message
-            let target_name = "abc\0";
-            let mut shm_mem = unsafe {
-                SharedMemory::new(target_name, data_size)
-                .unwrap()
+    // Each client gets its own thread: a failing or slow client only affects itself
+    thread::scope(|scope| {
+        for id in 1.. {
+            let slot = limit.acquire();
+            let stream = match listener.accept() {
+                Ok((stream, _addr)) => stream,
+                Err(e) => {
+                    // e.g. out of file descriptors, give the other clients time to finish
+                    println!("Could not accept a connection: {e}");
+                    thread::sleep(ACCEPT_RETRY_DELAY);
+                    continue;
+                }
             };
 
-            let write_channel = unsafe { shm_mem.as_slice_mut() };
-            write_channel.copy_from_slice(data_to_send.as_bytes());
+            let server = &server;
+            let spawned = thread::Builder::new()
+                            .name(format!("client-{id}"))
+                            .spawn_scoped(scope, move || {
+                                let _slot = slot;
+                                log!("Connection successful");
+                                match serve(stream, server) {
+                                    Ok(())  => log!("Connection closed"),
+                                    Err(e) => log!("Something went wrong: {}", Report(&e)),
+                                }
+                            });
+            if let Err(e) = spawned {
+                println!("Could not start serving connection {id}: {e}");
+            }
+        }
+    });
+}
 
-            println!("Wrote {data_size} bytes to shared memory {target_name}");
+fn serve(stream: UnixStream, server: &Server) -> Result<(), Error> {
+    stream.set_write_timeout(Some(Duration::from_secs(1)))?;
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    let peer = uds::peer_credentials(&stream)?;
+    log!("Client is process {} of user {} (group {})", peer.pid, peer.uid, peer.gid);
 
-            // Send the shared memory info to the client
-            stream.write_all(&data_size.to_ne_bytes()).unwrap();
-            uds::write_string_null_terminate(&mut stream, target_name).unwrap();
-        }
-        _ => {
-            println!("Something went wrong");
+    let mut reader = uds::Reader::new(&stream);
+
+    let negotiated = protocol::server_hello(&stream, &mut reader, server.offered)?;
+    log!("Speaking protocol version {}", negotiated.version);
+
+    // Credentials can't change during the connection
+    let access = server.policy.authorize(&peer);
+
+    // Regions handed out on this connection, which the client may still map.
+    // Holding them until it disconnects keeps the cache from evicting them meanwhile
+    let mut attached: Vec<Arc<SealedMemory>> = Vec::new();
+
+    // Requests are answered one after the other, the client may send the next ones meanwhile
+    while !reader.at_eof()? {
+        let (id, file_to_read) = match reader.receive()? {
+            Request::OpenFile { id, path } => (id, path),
+            Request::Close => break,
+        };
+        log!("Request {id}: reading {file_to_read} ...");
+
+        let prepared = match &access {
+            Err(denied) => {
+                log!("Refusing to share {file_to_read}: {denied}");
+                Err((ErrorKind::PermissionDenied, denied.to_string()))
+            }
+            // Failures to read the file are the client's business, report them
+            Ok(access) => prepare_transfer(server, access.root, &file_to_read, &negotiated)
+                            .map_err(|e| {
+                                log!("Could not share {file_to_read}: {}", Report(&e));
+                                (error_kind(&e), Report(&e).to_string())
+                            }),
+        };
+
+        match prepared {
+            Ok(Transfer::Whole(shm_mem)) => {
+                let response = Response::ShmHandle {
+                    id,
+                    size: shm_mem.len() as u64,
+                    fd:   shm_mem.as_fd().try_clone_to_owned()?,
+                };
+                protocol::send(&stream, &response)?;
+                if !attached.iter().any(|region| Arc::ptr_eq(region, &shm_mem)) {
+                    attached.push(shm_mem);
+                }
+            }
+            Ok(Transfer::Mapped { file, size }) => {
+                let response = Response::FileHandle {
+                    id,
+                    size,
+                    fd: file.into(),
+                };
+                protocol::send(&stream, &response)?;
+            }
+            Ok(Transfer::Stream { file, size, ring, writer }) => {
+                let response = Response::Stream {
+                    id,
+                    size,
+                    fd: ring.as_fd().try_clone_to_owned()?,
+                };
+                protocol::send(&stream, &response)?;
+                // The client notices a short stream on its own, keep serving it
+                match stream_file(file, size, writer) {
+                    Ok(()) => log!("Streamed {size} bytes of {file_to_read}"),
+                    Err(e) => log!("Could not stream {file_to_read}: {}", Report(&e)),
+                }
+                // Only unmap once the writer is done with the ring
+                drop(ring);
+            }
+            Err((kind, message)) => {
+                protocol::send(&stream, &Response::Error { id, kind, message })?;
+            }
         }
     }
+    Ok(())
+}
+
+/// How a file goes to the client
+enum Transfer {
+    /// Copied into sealed shared memory, possibly shared with other clients
+    Whole(Arc<SealedMemory>),
+    /// Handed out as is, for the client to map
+    Mapped { file: File, size: u64 },
+    /// Written through `ring` by `writer`, chunk by chunk
+    Stream {
+        file:   File,
+        size:   u64,
+        ring:   SharedMemory,
+        writer: BuildWriter,
+    },
+}
+
+/// Open the file and set up its transfer.
+/// The path is relative to the user's own directory if they have one, to the served one otherwise.
+fn prepare_transfer(server: &Server, user_root: Option<&Path>, file_to_read: &str, negotiated: &Negotiated) -> Result<Transfer, Error> {
+    let user_root = user_root.map(Root::open).transpose()?;
+    let root = user_root.as_ref().unwrap_or(&server.served_root);
+    let mut file = root.open_file(file_to_read)?;
+    let metadata = file.metadata()?;
+    let file_size = metadata.len();
+
+    // No copy at all, whatever the size
+    if negotiated.has(capabilities::MAPPED_FILE) {
+        return Ok(Transfer::Mapped { file, size: file_size });
+    }
+
+    // Memory use is bounded by the ring size, whatever the size of the file
+    if negotiated.has(capabilities::STREAM) && file_size > STREAM_THRESHOLD {
+        let mut ring = unsafe {
+            SharedMemory::anonymous(RING_DEBUG_NAME, RING_SIZE)?
+        };
+        // The client maps it writable: at least don't let it pull the pages from under us
+        ring.seal_size()?;
+        let writer = unsafe {
+            let ring_ptr = ring.as_slice_mut().as_mut_ptr();
+            stream::prepare_memory(ring_ptr, RING_SIZE).map_err(stream::Error::from)?;
+            BuildWriter::new(ring_ptr, RING_SIZE)?.with_timeout(IDLE_TIMEOUT)
+        };
+        return Ok(Transfer::Stream { file, size: file_size, ring, writer });
+    }
+
+    // Sealed memory can't change: if the file didn't either, reuse it
+    let cache_key = CacheKey::of(&metadata);
+    if let Some(shm_mem) = server.cache.get(&cache_key) {
+        log!("Found {file_to_read} in the cache");
+        return Ok(Transfer::Whole(shm_mem));
+    }
+
+    // Check the size first, rather than reading a huge file for nothing
+    if file_size > MAX_FILE_SIZE {
+        let message = format!("{file_size} bytes, at most {MAX_FILE_SIZE} are served");
+        return Err(io::Error::new(io::ErrorKind::FileTooLarge, message).into());
+    }
+
+    // Create a fresh anonymous shared memory object for this transfer
+    let data_size = file_size as usize;
+    let mut shm_mem = unsafe {
+        SharedMemory::anonymous(SHM_DEBUG_NAME, data_size)?
+    };
+
+    // Read the file straight into it, whatever its content.
+    // Fails if the file shrank in the meantime
+    let write_channel = unsafe { shm_mem.as_slice_mut() };
+    file.read_exact(write_channel)?;
+
+    log!("Wrote {data_size} bytes to shared memory");
+
+    // Prevent any further change so that the client can trust the content
+    let shm_mem = Arc::new(shm_mem.seal()?);
+    server.cache.insert(cache_key, Arc::clone(&shm_mem));
+    Ok(Transfer::Whole(shm_mem))
+}
+
+/// Write the first `size` bytes of the file to the ring, then close it.
+/// The ring must stay mapped until this returns.
+fn stream_file(mut file: File, size: u64, writer: BuildWriter) -> Result<(), Error> {
+    let mut writer = unsafe { writer.blocking_into()? };
+    let res = copy_to_stream(&mut file, size, &mut writer);
+    // Also on failure: the client stops waiting and sees the stream is short
+    unsafe { writer.close() };
+    res
+}
+
+fn copy_to_stream(file: &mut File, size: u64, writer: &mut StreamWriter) -> Result<(), Error> {
+    let mut chunk = vec![0; STREAM_CHUNK_SIZE];
+    let mut remaining = size;
+    while remaining > 0 {
+        let chunk_len = remaining.min(STREAM_CHUNK_SIZE as u64) as usize;
+        let read_len = match file.read(&mut chunk[..chunk_len]) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the file shrank while streaming").into()),
+            Ok(read_len) => read_len,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        unsafe { writer.write_all(&chunk[..read_len])? };
+        remaining -= read_len as u64;
+    }
+    Ok(())
+}
+
+fn error_kind(error: &Error) -> ErrorKind {
+    match error {
+        Error::Io(e) => ErrorKind::from_io(e),
+        _            => ErrorKind::Internal,
+    }
 }
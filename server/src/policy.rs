@@ -0,0 +1,118 @@
+//! Who may read files through the server
+//!
+//! Clients are identified by the credentials of their process (`SO_PEERCRED`),
+//! not by anything they send. The policy is read from the environment:
+//!
+//! - `SUMER_ALLOWED_UIDS`: comma separated users allowed to connect.
+//!   Defaults to the user running the server.
+//! - `SUMER_ALLOWED_GIDS`: comma separated groups allowed to connect,
+//!   matched against the primary group of the client.
+//! - `SUMER_USER_ROOTS`: comma separated `uid:directory` pairs.
+//!   Paths requested by these users are resolved under their directory
+//!   instead of the served one, and can't leave it (see `sandbox`).
+//!   The directories must be absolute.
+
+use std::{collections::HashMap, env, fmt, path::{Path, PathBuf}};
+
+use common::uds::PeerCredentials;
+
+pub struct Policy {
+    allowed_uids: Vec<libc::uid_t>,
+    allowed_gids: Vec<libc::gid_t>,
+    user_roots:   HashMap<libc::uid_t, PathBuf>,
+}
+
+/// Why the environment doesn't describe a valid policy
+#[derive(Debug)]
+pub struct ConfigError {
+    variable: &'static str,
+    value:    String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid value for {}: {:?}", self.variable, self.value)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// The client is not allowed to use the server
+#[derive(Debug)]
+pub struct Denied {
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+}
+
+impl fmt::Display for Denied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "user {} (group {}) is not allowed to read files", self.uid, self.gid)
+    }
+}
+
+impl std::error::Error for Denied {}
+
+/// What an authorized client may access
+pub struct Access<'a> {
+    /// Requested paths are resolved under this absolute directory, if any.
+    /// Only open them through `sandbox::Root`, never with `Path::join`
+    pub root: Option<&'a Path>,
+}
+
+impl Policy {
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let allowed_uids = match env::var("SUMER_ALLOWED_UIDS") {
+            Ok(value) => parse_ids("SUMER_ALLOWED_UIDS", &value)?,
+            Err(_)    => vec![unsafe { libc::getuid() }],
+        };
+        let allowed_gids = match env::var("SUMER_ALLOWED_GIDS") {
+            Ok(value) => parse_ids("SUMER_ALLOWED_GIDS", &value)?,
+            Err(_)    => Vec::new(),
+        };
+        let user_roots = match env::var("SUMER_USER_ROOTS") {
+            Ok(value) => parse_roots("SUMER_USER_ROOTS", &value)?,
+            Err(_)    => HashMap::new(),
+        };
+
+        Ok(Self {
+            allowed_uids,
+            allowed_gids,
+            user_roots,
+        })
+    }
+
+    pub fn authorize(&self, peer: &PeerCredentials) -> Result<Access<'_>, Denied> {
+        if !self.allowed_uids.contains(&peer.uid) && !self.allowed_gids.contains(&peer.gid) {
+            return Err(Denied { uid: peer.uid, gid: peer.gid });
+        }
+
+        Ok(Access {
+            root: self.user_roots.get(&peer.uid).map(PathBuf::as_path),
+        })
+    }
+}
+
+fn parse_ids(variable: &'static str, value: &str) -> Result<Vec<u32>, ConfigError> {
+    value
+        .split(',')
+        .filter(|id| !id.trim().is_empty())
+        .map(|id| id.trim().parse().map_err(|_| ConfigError { variable, value: value.to_owned() }))
+        .collect()
+}
+
+fn parse_roots(variable: &'static str, value: &str) -> Result<HashMap<libc::uid_t, PathBuf>, ConfigError> {
+    let invalid = || ConfigError { variable, value: value.to_owned() };
+
+    let mut roots = HashMap::new();
+    for entry in value.split(',').filter(|entry| !entry.trim().is_empty()) {
+        let (uid, dir) = entry.split_once(':').ok_or_else(invalid)?;
+        let uid = uid.trim().parse().map_err(|_| invalid())?;
+        // Relative ones would depend on where the server was started from
+        let dir = PathBuf::from(dir.trim());
+        if !dir.is_absolute() {
+            return Err(invalid());
+        }
+        roots.insert(uid, dir);
+    }
+    Ok(roots)
+}
@@ -0,0 +1,165 @@
+//! Keeps clients inside the served directory
+//!
+//! Requested paths are resolved one component at a time with `openat`,
+//! starting from a descriptor of the served directory, so that nothing
+//! in between can redirect us elsewhere. Paths are refused if they are absolute
+//! or contain `..`, and symbolic links are never followed, even if they
+//! would stay inside the directory.
+
+use std::{ffi::CString, fs::File, io, mem, os::{fd::{AsRawFd, FromRawFd, OwnedFd}, unix::ffi::OsStrExt}, path::{Component, Path}};
+
+pub struct Root {
+    dir: OwnedFd,
+}
+
+impl Root {
+    /// `path` is trusted: it may go through symbolic links
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let path = CString::new(path.as_os_str().as_bytes())?;
+        let fd = unsafe { libc::open(path.as_ptr(), libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            dir: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    /// Open a regular file below the root for reading.
+    /// Requests leaving the root fail with `PermissionDenied`.
+    pub fn open_file(&self, requested: &str) -> io::Result<File> {
+        let mut names = Vec::new();
+        for component in Path::new(requested).components() {
+            match component {
+                Component::Normal(name) => names.push(name),
+                Component::CurDir       => {},
+                Component::ParentDir    => return Err(denied("paths containing `..` are not served")),
+                Component::RootDir
+                | Component::Prefix(_)  => return Err(denied("absolute paths are not served")),
+            }
+        }
+        let Some((file_name, dir_names)) = names.split_last() else {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "no file name in the path"));
+        };
+
+        // Walk down the directories, holding only the current one open
+        let mut dir: Option<OwnedFd> = None;
+        for name in dir_names {
+            let parent = dir.as_ref().unwrap_or(&self.dir);
+            dir = Some(open_at(parent, name.as_bytes(), libc::O_RDONLY | libc::O_DIRECTORY)?);
+        }
+
+        // Don't block on FIFOs and the like, they are refused right after
+        let parent = dir.as_ref().unwrap_or(&self.dir);
+        let file = File::from(open_at(parent, file_name.as_bytes(), libc::O_RDONLY | libc::O_NONBLOCK)?);
+        if !file.metadata()?.is_file() {
+            return Err(denied("only regular files are served"));
+        }
+        Ok(file)
+    }
+}
+
+fn open_at(dir: &OwnedFd, name: &[u8], flags: libc::c_int) -> io::Result<OwnedFd> {
+    let name = CString::new(name)?;
+    let fd = unsafe { libc::openat(dir.as_raw_fd(), name.as_ptr(), flags | libc::O_NOFOLLOW | libc::O_CLOEXEC) };
+    if fd < 0 {
+        let err = io::Error::last_os_error();
+        // O_NOFOLLOW reports symbolic links as ELOOP, or ENOTDIR along with O_DIRECTORY
+        let maybe_link = matches!(err.raw_os_error(), Some(libc::ELOOP | libc::ENOTDIR));
+        if maybe_link && is_symlink_at(dir, &name) {
+            return Err(denied("symbolic links are not followed"));
+        }
+        return Err(err);
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+fn is_symlink_at(dir: &OwnedFd, name: &CString) -> bool {
+    let mut stat: libc::stat = unsafe { mem::zeroed() };
+    let res = unsafe { libc::fstatat(dir.as_raw_fd(), name.as_ptr(), &mut stat, libc::AT_SYMLINK_NOFOLLOW) };
+    res == 0 && stat.st_mode & libc::S_IFMT == libc::S_IFLNK
+}
+
+fn denied(reason: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Read, os::unix::fs::symlink, path::PathBuf, process};
+
+    use super::*;
+
+    /// Directory tree removed when dropped:
+    /// `root/inside.txt`, `root/sub/nested.txt` and `outside/secret.txt` next to it
+    struct Tree {
+        base: PathBuf,
+    }
+
+    impl Tree {
+        fn new(test_name: &str) -> Self {
+            let base = std::env::temp_dir().join(format!("sumer-sandbox-{}-{test_name}", process::id()));
+            let _ = fs::remove_dir_all(&base);
+            fs::create_dir_all(base.join("root/sub")).unwrap();
+            fs::create_dir_all(base.join("outside")).unwrap();
+            fs::write(base.join("root/inside.txt"), "inside").unwrap();
+            fs::write(base.join("root/sub/nested.txt"), "nested").unwrap();
+            fs::write(base.join("outside/secret.txt"), "secret").unwrap();
+            Self { base }
+        }
+
+        fn root(&self) -> Root {
+            Root::open(&self.base.join("root")).unwrap()
+        }
+    }
+
+    impl Drop for Tree {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.base);
+        }
+    }
+
+    fn assert_denied(result: io::Result<File>) {
+        let err = result.expect_err("the file should not be served");
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied, "{err}");
+    }
+
+    #[test]
+    fn serves_files_below_the_root() {
+        let tree = Tree::new("below");
+        let mut content = String::new();
+        tree.root().open_file("sub/./nested.txt").unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "nested");
+    }
+
+    #[test]
+    fn rejects_absolute_paths() {
+        let tree = Tree::new("absolute");
+        let secret = tree.base.join("outside/secret.txt");
+        assert_denied(tree.root().open_file(secret.to_str().unwrap()));
+    }
+
+    #[test]
+    fn rejects_parent_components() {
+        let tree = Tree::new("parent");
+        assert_denied(tree.root().open_file("../outside/secret.txt"));
+        // Even when it would end up inside the root
+        assert_denied(tree.root().open_file("sub/../inside.txt"));
+    }
+
+    #[test]
+    fn rejects_directory_links_leaving_the_root() {
+        let tree = Tree::new("dir-link");
+        symlink(tree.base.join("outside"), tree.base.join("root/escape")).unwrap();
+        assert_denied(tree.root().open_file("escape/secret.txt"));
+    }
+
+    #[test]
+    fn rejects_file_links_leaving_the_root() {
+        let tree = Tree::new("file-link");
+        symlink(tree.base.join("outside/secret.txt"), tree.base.join("root/secret.txt")).unwrap();
+        symlink("../../outside/secret.txt", tree.base.join("root/sub/relative.txt")).unwrap();
+        assert_denied(tree.root().open_file("secret.txt"));
+        assert_denied(tree.root().open_file("sub/relative.txt"));
+    }
+}